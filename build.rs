@@ -0,0 +1,60 @@
+//! Embeds the web UI from `static/` into the binary.
+//! Writes `assets.rs` to `OUT_DIR`, a table of each file's path relative to `static/`,
+//! and its contents. Fails the build if there's no `static/index.html`.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+fn main() -> io::Result<()> {
+    let static_dir =
+        Path::new(&env::var("CARGO_MANIFEST_DIR").expect("Set by cargo")).join("static");
+    println!("cargo:rerun-if-changed={}", static_dir.display());
+
+    if !static_dir.join("index.html").is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No web UI found, {} is missing",
+                static_dir.join("index.html").display()
+            ),
+        ));
+    }
+
+    let mut files = Vec::new();
+    find_files(&static_dir, &mut files)?;
+    files.sort();
+
+    let mut table = String::from("&[\n");
+    for path in files {
+        let name = path
+            .strip_prefix(&static_dir)
+            .expect("Found inside static_dir")
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        table.push_str(&format!(
+            "    ({:?}, include_bytes!({:?}) as &[u8]),\n",
+            name, path
+        ));
+    }
+    table.push(']');
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("Set by cargo"));
+    fs::write(out_dir.join("assets.rs"), table)
+}
+
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
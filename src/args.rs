@@ -0,0 +1,128 @@
+use crate::settings::Overrides;
+use failure::{format_err, Error};
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: edit_count [init] [options]
+
+Commands:
+    init                Create settings.toml by answering a few questions
+
+Options:
+    --config <path>     Use this settings file, instead of the one in the config directory
+    --web-port <port>   Serve the web UI on this port
+    --ws-port <port>    Serve websockets on this port
+    --static-dir <path> Serve the web UI from this directory, instead of the one built in
+    --help              Show this message
+
+Settings can also be given as EDIT_COUNT_ environment variables, such as EDIT_COUNT_WEB_PORT.
+Command line options take precedence over environment variables, which take precedence over settings.toml.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Init,
+    Help,
+}
+
+/// What was asked for on the command line.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub command: Option<Command>,
+    pub config: Option<PathBuf>,
+    pub static_dir: Option<PathBuf>,
+    pub overrides: Overrides,
+}
+
+impl Args {
+    /// Parse the arguments, not including the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Args, Error> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Accept both `--option value` and `--option=value`
+            let (name, inline) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format_err!("{} needs a value, see --help", name))
+            };
+
+            match name {
+                "init" if parsed.command.is_none() => parsed.command = Some(Command::Init),
+                "--help" | "-h" => parsed.command = Some(Command::Help),
+                "--config" => parsed.config = Some(PathBuf::from(value()?)),
+                "--static-dir" => parsed.static_dir = Some(PathBuf::from(value()?)),
+                "--web-port" => parsed.overrides.web_port = Some(parse_port(name, &value()?)?),
+                "--ws-port" => parsed.overrides.ws_port = Some(parse_port(name, &value()?)?),
+                _ => return Err(format_err!("Unknown argument {:?}, see --help", arg)),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn parse_port(name: &str, value: &str) -> Result<u16, Error> {
+    value
+        .parse()
+        .map_err(|_| format_err!("{} must be a port number, not {:?}", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, Error> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn no_arguments() {
+        let args = parse(&[]).unwrap();
+
+        assert_eq!(args.command, None);
+        assert_eq!(args.config, None);
+        assert_eq!(args.overrides.web_port, None);
+    }
+
+    #[test]
+    fn options_with_separate_and_inline_values() {
+        let args = parse(&[
+            "init",
+            "--config",
+            "/etc/edit_count.toml",
+            "--web-port=8080",
+            "--ws-port",
+            "8081",
+            "--static-dir=ui/dist",
+        ])
+        .unwrap();
+
+        assert_eq!(args.command, Some(Command::Init));
+        assert_eq!(args.config, Some(PathBuf::from("/etc/edit_count.toml")));
+        assert_eq!(args.static_dir, Some(PathBuf::from("ui/dist")));
+        assert_eq!(args.overrides.web_port, Some(8080));
+        assert_eq!(args.overrides.ws_port, Some(8081));
+    }
+
+    #[test]
+    fn help_wins_over_init() {
+        assert_eq!(parse(&["init", "-h"]).unwrap().command, Some(Command::Help));
+        assert_eq!(parse(&["--help"]).unwrap().command, Some(Command::Help));
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        let error = |args: &[&str]| parse(args).unwrap_err().to_string();
+
+        assert!(error(&["--config"]).contains("needs a value"));
+        assert!(error(&["--web-port", "http"]).contains("must be a port number"));
+        assert!(error(&["--web-port=70000"]).contains("must be a port number"));
+        assert!(error(&["--verbose"]).contains("Unknown argument"));
+        assert!(error(&["init", "init"]).contains("Unknown argument"));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::resolver::ResolvedAlbum;
+use crate::GroupType;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Folds bursts of filesystem events into a single recount per album.
+///
+/// The first event for an album opens a window,
+/// and every event for that album until the window closes is folded into one recount.
+#[derive(Debug)]
+pub struct Coalescer {
+    window: Duration,
+    pending: HashMap<(PathBuf, GroupType), Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    album: ResolvedAlbum,
+    tipe: GroupType,
+    /// When the window closes
+    due: Instant,
+    events: usize,
+}
+
+/// An album whose window has closed, and needs to be recounted.
+#[derive(Debug)]
+pub struct CoalescedAlbum {
+    pub album: ResolvedAlbum,
+    pub tipe: GroupType,
+    /// The number of raw filesystem events folded into this recount
+    pub events: usize,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Coalescer {
+        Coalescer {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Record an event affecting `album`.
+    pub fn touch(&mut self, tipe: GroupType, album: &ResolvedAlbum, now: Instant) {
+        self.defer(tipe, album, now + self.window);
+        if let Some(pending) = self.pending.get_mut(&(album.path.clone(), tipe)) {
+            pending.events += 1;
+        }
+    }
+
+    /// Read `album` again at `due` without recording an event,
+    /// unless a window is already open for it.
+    pub fn defer(&mut self, tipe: GroupType, album: &ResolvedAlbum, due: Instant) {
+        self.pending
+            .entry((album.path.clone(), tipe))
+            .or_insert_with(|| Pending {
+                album: album.clone(),
+                tipe,
+                due,
+                events: 0,
+            });
+    }
+
+    /// How long until the next window closes, if any albums are pending.
+    pub fn next_due(&self, now: Instant) -> Option<Duration> {
+        self.pending
+            .values()
+            .map(|p| p.due.saturating_duration_since(now))
+            .min()
+    }
+
+    /// Remove and return every album whose window has closed.
+    pub fn take_due(&mut self, now: Instant) -> Vec<CoalescedAlbum> {
+        let due: Vec<(PathBuf, GroupType)> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.due <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        due.into_iter()
+            .filter_map(|key| self.pending.remove(&key))
+            .map(|p| CoalescedAlbum {
+                album: p.album,
+                tipe: p.tipe,
+                events: p.events,
+            })
+            .collect()
+    }
+
+    /// Drop any pending recount of `album`, such as one that no longer exists.
+    pub fn forget(&mut self, tipe: GroupType, album: &ResolvedAlbum) {
+        self.pending.remove(&(album.path.clone(), tipe));
+    }
+
+    /// Remove and return every pending album, whether or not its window has closed.
+    pub fn take_all(&mut self) -> Vec<CoalescedAlbum> {
+        self.pending
+            .drain()
+            .map(|(_, p)| CoalescedAlbum {
+                album: p.album,
+                tipe: p.tipe,
+                events: p.events,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    fn album(name: &str) -> ResolvedAlbum {
+        ResolvedAlbum {
+            group_name: "2024".to_string(),
+            album_name: name.to_string(),
+            path: PathBuf::from("/photos/2024").join(name),
+        }
+    }
+
+    #[test]
+    fn events_in_a_window_are_folded_into_one_recount() {
+        let mut coalescer = Coalescer::new(WINDOW);
+        let start = Instant::now();
+        let wedding = album("Wedding");
+
+        coalescer.touch(GroupType::Raw, &wedding, start);
+        coalescer.touch(GroupType::Raw, &wedding, start + WINDOW / 2);
+        coalescer.touch(GroupType::Raw, &wedding, start + WINDOW / 2);
+
+        // The window doesn't move with later events
+        assert_eq!(coalescer.next_due(start + WINDOW / 2), Some(WINDOW / 2));
+        assert!(coalescer.take_due(start + WINDOW / 2).is_empty());
+
+        let due = coalescer.take_due(start + WINDOW);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].album.album_name, "Wedding");
+        assert_eq!(due[0].events, 3);
+        assert_eq!(coalescer.next_due(start + WINDOW), None);
+    }
+
+    #[test]
+    fn raw_and_render_sides_are_recounted_separately() {
+        let mut coalescer = Coalescer::new(WINDOW);
+        let start = Instant::now();
+        let wedding = album("Wedding");
+
+        coalescer.touch(GroupType::Raw, &wedding, start);
+        coalescer.touch(GroupType::Render, &wedding, start);
+        coalescer.touch(GroupType::Raw, &album("Party"), start + WINDOW);
+
+        let mut due: Vec<GroupType> = coalescer
+            .take_due(start + WINDOW)
+            .into_iter()
+            .map(|c| c.tipe)
+            .collect();
+        due.sort_by_key(|tipe| *tipe == GroupType::Render);
+        assert_eq!(due, vec![GroupType::Raw, GroupType::Render]);
+        assert_eq!(coalescer.take_all().len(), 1);
+    }
+
+    #[test]
+    fn deferred_reads_have_no_events_and_dont_move_open_windows() {
+        let mut coalescer = Coalescer::new(WINDOW);
+        let start = Instant::now();
+        let (wedding, party) = (album("Wedding"), album("Party"));
+
+        coalescer.defer(GroupType::Raw, &wedding, start + WINDOW * 10);
+        coalescer.touch(GroupType::Raw, &party, start);
+        coalescer.defer(GroupType::Raw, &party, start + WINDOW * 10);
+
+        let due = coalescer.take_due(start + WINDOW);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].album.album_name, "Party");
+        assert_eq!(due[0].events, 1);
+
+        let due = coalescer.take_due(start + WINDOW * 10);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].album.album_name, "Wedding");
+        assert_eq!(due[0].events, 0);
+    }
+
+    #[test]
+    fn forgotten_albums_are_not_recounted() {
+        let mut coalescer = Coalescer::new(WINDOW);
+        let start = Instant::now();
+        let wedding = album("Wedding");
+
+        coalescer.touch(GroupType::Raw, &wedding, start);
+        coalescer.forget(GroupType::Raw, &wedding);
+
+        assert!(coalescer.take_due(start + WINDOW).is_empty());
+    }
+}
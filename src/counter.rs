@@ -1,54 +1,109 @@
-use crate::worker::{Worker, WorkerError, WorkerResult};
-use crate::{Count, CountUpdateEvent, DirectoryUpdateEvent, GroupType, SetEvent};
+use crate::supervisor::SupervisedSender;
+use crate::worker::{Shutdown, Worker, WorkerError, WorkerResult, SHUTDOWN_CHECK_INTERVAL};
+use crate::{
+    Count, CountUpdateEvent, DirectoryUpdateEvent, GroupType, OfflineEvent, RetainEvent, SetEvent,
+};
 use derive_new::new;
+use edit_count::protocol;
 use failure::Error;
-use std::collections::{HashMap, HashSet};
-use std::ffi::OsString;
-use std::sync::mpsc::{Receiver, Sender};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
 
 #[derive(new)]
 pub struct Counter {
-    cue_tx: Sender<CountUpdateEvent>,
+    cue_tx: SupervisedSender<CountUpdateEvent>,
     due_rx: Receiver<DirectoryUpdateEvent>,
-    #[new(default)]
-    tree: Arc<Mutex<CountTree>>,
-}
-
-impl Counter {
-    pub fn get_handle(&self) -> CounterHandle {
-        CounterHandle(self.tree.clone())
-    }
+    /// How long to keep the totals of removed albums, in milliseconds
+    removed_album_retention: u64,
+    /// Shared with the previous Counter, so counts survive a restart
+    tree: CounterHandle,
 }
 
 impl Worker for Counter {
     type W = Counter;
     const NAME: &'static str = "Counter";
 
-    fn work(self) -> WorkerResult {
-        while let Ok(event) = self.due_rx.recv() {
-            let mut tree = self.tree.lock().or(Err(WorkerError::ResourcePoisoned {
+    fn work(self, shutdown: Shutdown) -> WorkerResult {
+        let retention = Duration::from_millis(self.removed_album_retention);
+        loop {
+            // Only stop once every queued update has been counted
+            let event = match self.due_rx.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) if shutdown.is_triggered() => break,
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            let mut tree = self.tree.0.lock().or(Err(WorkerError::ResourcePoisoned {
                 name: "Counter.tree".to_string(),
             }))?;
 
+            let expired = tree.expire(retention, SystemTime::now());
+            if expired > 0 {
+                debug!(expired, "Forgot the totals of removed albums");
+            }
+
+            let event = match event {
+                Some(event) => event,
+                None => continue,
+            };
+
             match event {
                 DirectoryUpdateEvent::Exist(path) => {
                     // TODO: Implement
-                    println!("Exist!({:?})", path)
+                    debug!(path = ?path, "Exist")
                 }
 
                 DirectoryUpdateEvent::Remove(path) => {
                     // TODO: Implement
-                    println!("Remove!({:?})", path)
+                    debug!(path = ?path, "Remove")
                 }
 
                 DirectoryUpdateEvent::Set(event) => {
-                    let cue = tree.set(event)?;
-                    if cue.count.total > 0 {
+                    debug!(
+                        group = %event.group_name,
+                        album = %event.album_name,
+                        tipe = ?event.tipe,
+                        files = event.files.len(),
+                        events = event.events,
+                        "Counting album"
+                    );
+                    if let Some(cue) = tree.set(event)? {
+                        if cue.removed {
+                            info!(group = %cue.group_name, album = %cue.album_name, "Album removed");
+                        }
+                        if cue.count.total > 0 || cue.removed {
+                            self.cue_tx.send(cue)?;
+                        }
+                    }
+                }
+
+                DirectoryUpdateEvent::Offline(event) => {
+                    for cue in tree.set_offline(event)? {
                         self.cue_tx.send(cue)?;
                     }
                 }
 
+                DirectoryUpdateEvent::Retain(event) => {
+                    let cues = tree.retain(&event)?;
+                    let dropped = cues.iter().filter(|c| c.removed).count();
+                    if dropped > 0 {
+                        info!(dropped, "Dropped albums that no longer match the layout");
+                    }
+                    for cue in cues {
+                        if cue.count.total > 0 || cue.removed {
+                            self.cue_tx.send(cue)?;
+                        }
+                    }
+                }
+
                 DirectoryUpdateEvent::Refresh => {
                     for cue in tree.full_count()? {
                         self.cue_tx.send(cue)?;
@@ -61,28 +116,285 @@ impl Worker for Counter {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct CounterHandle(Arc<Mutex<CountTree>>);
 
 impl CounterHandle {
-    pub fn full_count(&self) -> Result<Vec<CountUpdateEvent>, Error> {
-        self.0
-            .lock()
-            .or(Err(WorkerError::new_resource_poisoned(
-                "Counter.tree".to_string(),
-            )))?
-            .full_count()
+    pub fn snapshot(&self) -> Result<Catchup, Error> {
+        self.lock()?.snapshot()
+    }
+
+    pub fn changes_since(&self, nonce: u64, since: u64) -> Result<Catchup, Error> {
+        self.lock()?.changes_since(nonce, since)
+    }
+
+    pub fn album_counts(&self) -> Result<Vec<AlbumCount>, Error> {
+        self.lock()?.album_counts()
+    }
+
+    pub fn group_counts(&self) -> Result<Vec<GroupCount>, Error> {
+        self.lock()?.group_counts()
+    }
+
+    /// The counts for each album in a group, or None if there's no such group.
+    pub fn group_albums(&self, group_name: &str) -> Result<Option<Vec<AlbumCount>>, Error> {
+        self.lock()?.group_albums(group_name)
+    }
+
+    pub fn album_count(
+        &self,
+        group_name: &str,
+        album_name: &str,
+    ) -> Result<Option<AlbumCount>, Error> {
+        self.lock()?.album_count(group_name, album_name)
+    }
+
+    /// What still needs editing in an album, or None if there's no such album.
+    pub fn album_files(
+        &self,
+        group_name: &str,
+        album_name: &str,
+    ) -> Result<Option<AlbumFiles>, Error> {
+        Ok(self.lock()?.album_files(group_name, album_name))
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, CountTree>, Error> {
+        Ok(self.0.lock().or(Err(WorkerError::new_resource_poisoned(
+            "Counter.tree".to_string(),
+        )))?)
     }
 }
 
-#[derive(Debug, Default, new, Clone)]
+/// The counts for one album, for reporting.
+#[derive(Debug, Clone)]
+pub struct AlbumCount {
+    pub group_name: String,
+    pub album_name: String,
+    pub count: Count,
+    /// Raw files with a render of the same name
+    pub edited: usize,
+    pub offline_since: Option<SystemTime>,
+    /// When a filesystem event last changed the album, if one has since startup
+    pub last_activity: Option<SystemTime>,
+}
+
+impl AlbumCount {
+    /// Each count, with its name.
+    pub fn kinds(&self) -> [(&'static str, usize); 4] {
+        [
+            ("total", self.count.total),
+            ("raw", self.count.raw),
+            ("render", self.count.render),
+            ("edited", self.edited),
+        ]
+    }
+
+    /// Raw files that were counted, but have since been deleted.
+    pub fn deleted(&self) -> usize {
+        self.count.total.saturating_sub(self.count.raw)
+    }
+
+    /// The fraction of raw files that have been edited or deleted.
+    pub fn completion(&self) -> f64 {
+        completion(self.count.total, self.edited + self.deleted())
+    }
+
+    /// Whether every remaining raw file has been edited.
+    pub fn is_complete(&self) -> bool {
+        self.edited >= self.count.raw
+    }
+}
+
+impl From<AlbumCount> for protocol::AlbumSummary {
+    fn from(album: AlbumCount) -> protocol::AlbumSummary {
+        protocol::AlbumSummary {
+            total: album.count.total,
+            raw: album.count.raw,
+            render: album.count.render,
+            edited: album.edited,
+            deleted: album.deleted(),
+            completion: album.completion(),
+            complete: album.is_complete(),
+            offline: album.offline_since.is_some(),
+            offline_since: unix_secs(album.offline_since),
+            last_activity: unix_secs(album.last_activity),
+            group_name: album.group_name,
+            album_name: album.album_name,
+        }
+    }
+}
+
+/// The counts for every album in a group, for reporting.
+#[derive(Debug, Clone)]
+pub struct GroupCount {
+    pub group_name: String,
+    pub albums: usize,
+    pub incomplete_albums: usize,
+    pub count: Count,
+    pub edited: usize,
+    /// The latest activity in any of the group's albums
+    pub last_activity: Option<SystemTime>,
+}
+
+impl GroupCount {
+    fn new(group_name: String, albums: &[AlbumCount]) -> GroupCount {
+        let sum = |f: fn(&AlbumCount) -> usize| albums.iter().map(f).sum();
+        GroupCount {
+            group_name,
+            albums: albums.len(),
+            incomplete_albums: albums.iter().filter(|a| !a.is_complete()).count(),
+            count: Count {
+                total: sum(|a| a.count.total),
+                raw: sum(|a| a.count.raw),
+                render: sum(|a| a.count.render),
+            },
+            edited: sum(|a| a.edited),
+            last_activity: albums.iter().filter_map(|a| a.last_activity).max(),
+        }
+    }
+
+    /// Raw files that were counted, but have since been deleted.
+    pub fn deleted(&self) -> usize {
+        self.count.total.saturating_sub(self.count.raw)
+    }
+
+    /// The fraction of raw files that have been edited or deleted.
+    pub fn completion(&self) -> f64 {
+        completion(self.count.total, self.edited + self.deleted())
+    }
+
+    /// Whether every album in the group is complete.
+    pub fn is_complete(&self) -> bool {
+        self.incomplete_albums == 0
+    }
+}
+
+impl From<GroupCount> for protocol::GroupSummary {
+    fn from(group: GroupCount) -> protocol::GroupSummary {
+        protocol::GroupSummary {
+            albums: group.albums,
+            incomplete_albums: group.incomplete_albums,
+            total: group.count.total,
+            raw: group.count.raw,
+            render: group.count.render,
+            edited: group.edited,
+            deleted: group.deleted(),
+            completion: group.completion(),
+            complete: group.is_complete(),
+            last_activity: unix_secs(group.last_activity),
+            group_name: group.group_name,
+        }
+    }
+}
+
+/// An album's files that still need attention, sorted by name.
+#[derive(Debug, Clone)]
+pub struct AlbumFiles {
+    pub group_name: String,
+    pub album_name: String,
+    /// Raw files with no render of the same name
+    pub unedited: Vec<String>,
+    /// Renders with no raw file of the same name
+    pub unmatched_renders: Vec<String>,
+    /// Raw files that were counted, but have since been deleted
+    pub deleted: Vec<String>,
+}
+
+impl From<AlbumFiles> for protocol::AlbumFiles {
+    fn from(files: AlbumFiles) -> protocol::AlbumFiles {
+        protocol::AlbumFiles {
+            group_name: files.group_name,
+            album_name: files.album_name,
+            unedited: files.unedited,
+            unmatched_renders: files.unmatched_renders,
+            deleted: files.deleted,
+        }
+    }
+}
+
+/// `done` out of `total`, with nothing to do counting as done.
+fn completion(total: usize, done: usize) -> f64 {
+    if total == 0 {
+        1.0
+    } else {
+        done as f64 / total as f64
+    }
+}
+
+/// The update for an album that no longer exists.
+fn removal_cue(group_name: &str, album_name: &str, sequence: u64) -> CountUpdateEvent {
+    CountUpdateEvent {
+        group_name: group_name.to_string(),
+        album_name: album_name.to_string(),
+        count: Count {
+            total: 0,
+            raw: 0,
+            render: 0,
+        },
+        edited: 0,
+        offline_since: None,
+        events: 0,
+        sequence,
+        removed: true,
+    }
+}
+
+fn unix_secs(time: Option<SystemTime>) -> Option<u64> {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+/// The number of changes kept for clients resuming a session
+const CHANGE_LOG_SIZE: usize = 4096;
+
+#[derive(Debug, new, Clone)]
 pub struct CountTree {
     #[new(default)]
     counts: HashMap<String, Group>,
     #[new(default)]
     totals: Totals,
+    /// Identifies this tree, so sequence numbers from a previous run aren't trusted
+    #[new(value = "new_nonce()")]
+    nonce: u64,
+    /// The sequence number of the latest change
+    #[new(default)]
+    sequence: u64,
+    /// The latest changes, oldest first
     #[new(default)]
-    nonce: usize,
+    changes: VecDeque<Change>,
+    /// Removed albums whose totals are still kept, and when they were removed
+    #[new(default)]
+    removed: HashMap<(String, String), SystemTime>,
+}
+
+impl Default for CountTree {
+    fn default() -> CountTree {
+        CountTree::new()
+    }
+}
+
+/// A change to an album's counts.
+#[derive(Debug, Clone)]
+struct Change {
+    sequence: u64,
+    group_name: String,
+    album_name: String,
+}
+
+/// A random nonce that fits in a JSON number.
+fn new_nonce() -> u64 {
+    RandomState::new().build_hasher().finish() & ((1 << 53) - 1)
+}
+
+/// The counts a client needs to catch up.
+#[derive(Debug, Clone)]
+pub struct Catchup {
+    pub nonce: u64,
+    /// The sequence number the counts are current to
+    pub sequence: u64,
+    /// Only the albums changed since the client's sequence number, rather than every album
+    pub partial: bool,
+    pub albums: Vec<CountUpdateEvent>,
 }
 
 impl CountTree {
@@ -90,39 +402,180 @@ impl CountTree {
         let mut counts = Vec::new();
         for (group_name, group) in self.counts.iter() {
             for (album_name, album) in group.iter() {
-                let raw_count = album.raw_cache.len();
-                let render_count = album.render_cache.len();
-                let total_count = self.totals.get_count(&album_name, &group_name)?;
-
-                let cue = CountUpdateEvent {
-                    group_name: group_name.clone(),
-                    album_name: album_name.clone(),
-                    count: Count {
-                        total: total_count,
-                        raw: raw_count,
-                        render: render_count,
-                    },
-                };
+                counts.push(self.album_cue(group_name, album_name, album, 0)?);
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Every album's counts, and the sequence number they're current to.
+    pub fn snapshot(&self) -> Result<Catchup, Error> {
+        Ok(Catchup {
+            nonce: self.nonce,
+            sequence: self.sequence,
+            partial: false,
+            albums: self.full_count()?,
+        })
+    }
+
+    /// The counts of the albums changed after `since`.
+    /// Falls back to a snapshot if the changes are no longer logged, or came from another tree.
+    pub fn changes_since(&self, nonce: u64, since: u64) -> Result<Catchup, Error> {
+        let oldest = self
+            .changes
+            .front()
+            .map_or(self.sequence + 1, |c| c.sequence);
+        if nonce != self.nonce || since > self.sequence || since + 1 < oldest {
+            return self.snapshot();
+        }
+
+        // Newest first, so albums that no longer exist are reported with their removal
+        let mut changed = HashSet::new();
+        let mut albums = Vec::new();
+        for change in self.changes.iter().rev().take_while(|c| c.sequence > since) {
+            if !changed.insert((&change.group_name, &change.album_name)) {
+                continue;
+            }
+            let cue = match self
+                .counts
+                .get(&change.group_name)
+                .and_then(|g| g.get(&change.album_name))
+            {
+                Some(album) => self.album_cue(&change.group_name, &change.album_name, album, 0)?,
+                None => removal_cue(&change.group_name, &change.album_name, change.sequence),
+            };
+            albums.push(cue);
+        }
+        albums.reverse();
+
+        Ok(Catchup {
+            nonce: self.nonce,
+            sequence: self.sequence,
+            partial: true,
+            albums,
+        })
+    }
+
+    pub fn album_counts(&self) -> Result<Vec<AlbumCount>, Error> {
+        let mut counts = Vec::new();
+        for (group_name, group) in self.counts.iter() {
+            for (album_name, album) in group.iter() {
+                counts.push(self.count_album(group_name, album_name, album)?);
+            }
+        }
+
+        Ok(counts)
+    }
 
-                counts.push(cue);
+    pub fn group_counts(&self) -> Result<Vec<GroupCount>, Error> {
+        let mut counts = Vec::new();
+        for group_name in self.counts.keys() {
+            if let Some(albums) = self.group_albums(group_name)? {
+                counts.push(GroupCount::new(group_name.clone(), &albums));
             }
         }
 
         Ok(counts)
     }
 
-    pub fn set(&mut self, event: SetEvent) -> Result<CountUpdateEvent, Error> {
-        let mut raw_count = 0;
-        let mut render_count = 0;
+    pub fn group_albums(&self, group_name: &str) -> Result<Option<Vec<AlbumCount>>, Error> {
+        let group = match self.counts.get(group_name) {
+            Some(g) => g,
+            None => return Ok(None),
+        };
+
+        let mut counts = Vec::with_capacity(group.len());
+        for (album_name, album) in group.iter() {
+            counts.push(self.count_album(group_name, album_name, album)?);
+        }
+
+        Ok(Some(counts))
+    }
+
+    pub fn album_count(
+        &self,
+        group_name: &str,
+        album_name: &str,
+    ) -> Result<Option<AlbumCount>, Error> {
+        match self.counts.get(group_name).and_then(|g| g.get(album_name)) {
+            Some(album) => Ok(Some(self.count_album(group_name, album_name, album)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn album_files(&self, group_name: &str, album_name: &str) -> Option<AlbumFiles> {
+        let album = self.counts.get(group_name)?.get(album_name)?;
+        let raw_stems = stems(&album.raw_cache);
+        let render_stems = stems(&album.render_cache);
+        let without = |files: &HashSet<OsString>, stems: &HashSet<&OsStr>| {
+            sorted_names(
+                files
+                    .iter()
+                    .filter(|f| Path::new(f).file_stem().is_none_or(|s| !stems.contains(s))),
+            )
+        };
+
+        let counted = self.totals.get(album_name, group_name);
+        let deleted = sorted_names(
+            counted
+                .iter()
+                .flat_map(|c| c.iter())
+                .filter(|f| !album.raw_cache.contains(*f)),
+        );
+
+        Some(AlbumFiles {
+            group_name: group_name.to_string(),
+            album_name: album_name.to_string(),
+            unedited: without(&album.raw_cache, &render_stems),
+            unmatched_renders: without(&album.render_cache, &raw_stems),
+            deleted,
+        })
+    }
+
+    fn count_album(
+        &self,
+        group_name: &str,
+        album_name: &str,
+        album: &Album,
+    ) -> Result<AlbumCount, Error> {
+        Ok(AlbumCount {
+            group_name: group_name.to_string(),
+            album_name: album_name.to_string(),
+            count: Count {
+                total: self.totals.get_count(album_name, group_name)?,
+                raw: album.raw_cache.len(),
+                render: album.render_cache.len(),
+            },
+            edited: album.edited(),
+            offline_since: album.offline_since(),
+            last_activity: album.last_activity,
+        })
+    }
+
+    /// Count an album, returning its update, or None if it's empty and wasn't known.
+    /// Albums left with no files are removed.
+    pub fn set(&mut self, event: SetEvent) -> Result<Option<CountUpdateEvent>, Error> {
+        let known = self
+            .counts
+            .get(&event.group_name)
+            .is_some_and(|g| g.contains_key(&event.album_name));
+        if !known && event.files.is_empty() {
+            return Ok(None);
+        }
+
+        // Crawls recount albums without anything having changed
+        let activity = if event.events > 0 {
+            Some(SystemTime::now())
+        } else {
+            None
+        };
 
         // Ensure only raw files are added to the total counts
         if event.tipe == GroupType::Raw {
             self.totals
                 .update_count(&event.album_name, &event.group_name, &event.files)?;
         }
-        let total_count = self
-            .totals
-            .get_count(&event.album_name, &event.group_name)?;
 
         // Convert files to HashSet
         let mut file_set = HashSet::with_capacity(event.files.len());
@@ -136,35 +589,40 @@ impl CountTree {
             // If the album already exists
             Some(album) => {
                 // Update the existing album
+                // A crawl means the root is back online
                 match event.tipe {
                     GroupType::Raw => {
                         album.raw_cache = file_set;
+                        album.raw_path = Some(event.path);
+                        album.raw_offline = None;
                     }
                     GroupType::Render => {
                         album.render_cache = file_set;
+                        album.render_path = Some(event.path);
+                        album.render_offline = None;
                     }
                 }
-                raw_count = album.raw_cache.len();
-                render_count = album.render_cache.len();
+                album.last_activity = activity.or(album.last_activity);
             }
 
             // If the album doesn't already exist
             None => {
                 // Create a new album
-                let (raw_cache, render_cache) = match event.tipe {
-                    GroupType::Raw => {
-                        raw_count = file_set.len();
-                        (file_set, HashSet::new())
-                    }
-                    GroupType::Render => {
-                        render_count = file_set.len();
-                        (HashSet::new(), file_set)
-                    }
+                let path = Some(event.path);
+                let (raw_cache, render_cache, raw_path, render_path) = match event.tipe {
+                    GroupType::Raw => (file_set, HashSet::new(), path, None),
+                    GroupType::Render => (HashSet::new(), file_set, None, path),
                 };
 
                 let album = Album {
                     raw_cache,
                     render_cache,
+                    raw_path,
+                    render_path,
+                    raw_offline: None,
+                    render_offline: None,
+                    last_activity: activity,
+                    sequence: 0,
                 };
 
                 // Add the new album
@@ -172,29 +630,198 @@ impl CountTree {
             }
         }
 
+        let key = (event.group_name, event.album_name);
+        if self.counts[&key.0][&key.1].is_empty() {
+            return Ok(Some(self.remove_album(&key.0, &key.1, event.events)));
+        }
+        // An album that returns keeps the totals from before it was removed
+        self.removed.remove(&key);
+
+        self.record(&key.0, &key.1);
+        let album = &self.counts[&key.0][&key.1];
+
+        self.album_cue(&key.0, &key.1, album, event.events)
+            .map(Some)
+    }
+
+    /// Remove an album that has no files left, and its group if it was the last album.
+    /// Its totals are kept until they expire, in case it returns.
+    fn remove_album(
+        &mut self,
+        group_name: &str,
+        album_name: &str,
+        events: usize,
+    ) -> CountUpdateEvent {
+        if let Some(group) = self.counts.get_mut(group_name) {
+            group.remove(album_name);
+            if group.is_empty() {
+                self.counts.remove(group_name);
+            }
+        }
+        self.removed.insert(
+            (group_name.to_string(), album_name.to_string()),
+            SystemTime::now(),
+        );
+
+        self.record(group_name, album_name);
+        let mut cue = removal_cue(group_name, album_name, self.sequence);
+        cue.events = events;
+        cue
+    }
+
+    /// Forget the totals of albums removed more than `retention` before `now`.
+    /// Returns the number of albums forgotten.
+    pub fn expire(&mut self, retention: Duration, now: SystemTime) -> usize {
+        let CountTree {
+            totals, removed, ..
+        } = self;
+        let before = removed.len();
+        removed.retain(|(group_name, album_name), since| {
+            let keep = now.duration_since(*since).unwrap_or_default() < retention;
+            if !keep {
+                totals.remove(album_name, group_name);
+            }
+            keep
+        });
+
+        before - removed.len()
+    }
+
+    /// Mark every album read from an unmounted root as offline.
+    /// Their last-known files are kept, so nothing is counted as deleted.
+    pub fn set_offline(&mut self, event: OfflineEvent) -> Result<Vec<CountUpdateEvent>, Error> {
+        let mut offline = Vec::new();
+        for (group_name, group) in self.counts.iter_mut() {
+            for (album_name, album) in group.iter_mut() {
+                let (path, since) = match event.tipe {
+                    GroupType::Raw => (&album.raw_path, &mut album.raw_offline),
+                    GroupType::Render => (&album.render_path, &mut album.render_offline),
+                };
+                if !path.as_ref().is_some_and(|p| p.starts_with(&event.root)) {
+                    continue;
+                }
+
+                // Keep the original timestamp if the root was already offline
+                since.get_or_insert(event.since);
+                offline.push((group_name.clone(), album_name.clone()));
+            }
+        }
+
+        let mut counts = Vec::new();
+        for (group_name, album_name) in offline {
+            self.record(&group_name, &album_name);
+            let album = &self.counts[&group_name][&album_name];
+            counts.push(self.album_cue(&group_name, &album_name, album, 0)?);
+        }
+
+        Ok(counts)
+    }
+
+    /// Forget the raw or render side of every album the layout no longer matches,
+    /// and remove albums with neither side. Albums on missing roots are kept.
+    /// Returns an update for every album changed.
+    pub fn retain(&mut self, event: &RetainEvent) -> Result<Vec<CountUpdateEvent>, Error> {
+        let CountTree { counts, totals, .. } = self;
+        let mut cleared = Vec::new();
+        let mut dropped = Vec::new();
+        for (group_name, group) in counts.iter_mut() {
+            for (album_name, album) in group.iter_mut() {
+                let keep = |tipe, path: &Option<PathBuf>| {
+                    event
+                        .albums
+                        .contains(&(tipe, group_name.clone(), album_name.clone()))
+                        || event.missing_roots.iter().any(|(root_type, root)| {
+                            *root_type == tipe && path.as_ref().is_some_and(|p| p.starts_with(root))
+                        })
+                };
+                let keep_raw = keep(GroupType::Raw, &album.raw_path);
+                let keep_render = keep(GroupType::Render, &album.render_path);
+
+                // Removed like any other album, so its totals are kept until they expire
+                if !keep_raw && !keep_render {
+                    dropped.push((group_name.clone(), album_name.clone()));
+                    continue;
+                }
+
+                let mut changed = false;
+                if !keep_raw {
+                    changed |= !album.raw_cache.is_empty();
+                    album.raw_cache.clear();
+                    album.raw_path = None;
+                    album.raw_offline = None;
+                    totals.remove(album_name, group_name);
+                }
+                if !keep_render {
+                    changed |= !album.render_cache.is_empty();
+                    album.render_cache.clear();
+                    album.render_path = None;
+                    album.render_offline = None;
+                }
+                if changed {
+                    cleared.push((group_name.clone(), album_name.clone()));
+                }
+            }
+        }
+
+        let mut cues = Vec::new();
+        for (group_name, album_name) in cleared {
+            self.record(&group_name, &album_name);
+            let album = &self.counts[&group_name][&album_name];
+            cues.push(self.album_cue(&group_name, &album_name, album, 0)?);
+        }
+        for (group_name, album_name) in dropped {
+            cues.push(self.remove_album(&group_name, &album_name, 0));
+        }
+
+        Ok(cues)
+    }
+
+    /// Give a change to an album the next sequence number, and log it.
+    fn record(&mut self, group_name: &str, album_name: &str) {
+        self.sequence += 1;
+        if self.changes.len() == CHANGE_LOG_SIZE {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(Change {
+            sequence: self.sequence,
+            group_name: group_name.to_string(),
+            album_name: album_name.to_string(),
+        });
+
+        if let Some(album) = self
+            .counts
+            .get_mut(group_name)
+            .and_then(|g| g.get_mut(album_name))
+        {
+            album.sequence = self.sequence;
+        }
+    }
+
+    fn album_cue(
+        &self,
+        group_name: &str,
+        album_name: &str,
+        album: &Album,
+        events: usize,
+    ) -> Result<CountUpdateEvent, Error> {
         Ok(CountUpdateEvent {
-            group_name: event.group_name,
-            album_name: event.album_name,
+            group_name: group_name.to_string(),
+            album_name: album_name.to_string(),
             count: Count {
-                total: total_count,
-                raw: raw_count,
-                render: render_count,
+                total: self.totals.get_count(album_name, group_name)?,
+                raw: album.raw_cache.len(),
+                render: album.render_cache.len(),
             },
+            edited: album.edited(),
+            offline_since: album.offline_since(),
+            events,
+            sequence: album.sequence,
+            removed: false,
         })
     }
 
     fn get_group(&mut self, name: &str) -> &mut Group {
-        if !self.counts.contains_key(name) {
-            self.counts.insert(name.to_string(), Group::new());
-        }
-
-        self.counts.get_mut(name).expect(
-            format!(
-                "CountTree.counts does not contain {}, this should not happen.",
-                name
-            )
-            .as_str(),
-        )
+        self.counts.entry(name.to_string()).or_default()
     }
 }
 
@@ -210,20 +837,10 @@ impl Totals {
     ) -> WorkerResult {
         let key = format!("{}\n{}", album_name, group_name);
 
-        let album = match self.0.get_mut(&key) {
-            Some(a) => a,
-            None => {
-                self.0
-                    .insert(key.clone(), HashSet::with_capacity(names.len()));
-                self.0.get_mut(&key).expect(
-                    format!(
-                        "Totals.totals does not contain {}, this should not happen.",
-                        key
-                    )
-                    .as_str(),
-                )
-            }
-        };
+        let album = self
+            .0
+            .entry(key)
+            .or_insert_with(|| HashSet::with_capacity(names.len()));
 
         for name in names {
             // HashSet::insert will not update the set if the key already exists
@@ -234,6 +851,17 @@ impl Totals {
         Ok(())
     }
 
+    pub fn remove(&mut self, album_name: &str, group_name: &str) {
+        let key = format!("{}\n{}", album_name, group_name);
+        self.0.remove(&key);
+    }
+
+    /// Every raw file counted in an album.
+    pub fn get(&self, album_name: &str, group_name: &str) -> Option<&HashSet<OsString>> {
+        let key = format!("{}\n{}", album_name, group_name);
+        self.0.get(&key)
+    }
+
     pub fn get_count(&self, album_name: &str, group_name: &str) -> Result<usize, Error> {
         let key = format!("{}\n{}", album_name, group_name);
         match self.0.get(&key) {
@@ -249,4 +877,179 @@ type Group = HashMap<String, Album>;
 struct Album {
     raw_cache: HashSet<OsString>,
     render_cache: HashSet<OsString>,
+    /// The directory the raw files were last read from
+    raw_path: Option<PathBuf>,
+    /// The directory the renders were last read from
+    render_path: Option<PathBuf>,
+    /// When the raw layout root holding this album went offline
+    raw_offline: Option<SystemTime>,
+    /// When the render layout root holding this album went offline
+    render_offline: Option<SystemTime>,
+    /// When a filesystem event last changed the album, if one has since startup
+    last_activity: Option<SystemTime>,
+    /// The sequence number of the album's latest change
+    sequence: u64,
+}
+
+impl Album {
+    /// Whether neither side has any files, and neither is offline.
+    fn is_empty(&self) -> bool {
+        self.raw_cache.is_empty() && self.render_cache.is_empty() && self.offline_since().is_none()
+    }
+
+    /// The earliest time either side of the album went offline.
+    fn offline_since(&self) -> Option<SystemTime> {
+        match (self.raw_offline, self.render_offline) {
+            (Some(raw), Some(render)) => Some(raw.min(render)),
+            (raw, render) => raw.or(render),
+        }
+    }
+
+    /// The number of raw files that have a render with the same file stem.
+    fn edited(&self) -> usize {
+        let rendered = stems(&self.render_cache);
+
+        self.raw_cache
+            .iter()
+            .filter(|f| {
+                Path::new(f)
+                    .file_stem()
+                    .is_some_and(|s| rendered.contains(s))
+            })
+            .count()
+    }
+}
+
+fn stems(files: &HashSet<OsString>) -> HashSet<&OsStr> {
+    files
+        .iter()
+        .filter_map(|f| Path::new(f).file_stem())
+        .collect()
+}
+
+fn sorted_names<'a>(files: impl Iterator<Item = &'a OsString>) -> Vec<String> {
+    let mut names: Vec<String> = files.map(|f| f.to_string_lossy().into_owned()).collect();
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(tree: &mut CountTree, album_name: &str, files: &[&str]) -> Option<CountUpdateEvent> {
+        tree.set(SetEvent {
+            group_name: "2024".to_string(),
+            album_name: album_name.to_string(),
+            path: PathBuf::from("/photos/2024").join(album_name),
+            tipe: GroupType::Raw,
+            files: files.iter().map(OsString::from).collect(),
+            events: 1,
+        })
+        .unwrap()
+    }
+
+    fn albums(catchup: &Catchup) -> Vec<(&str, bool)> {
+        catchup
+            .albums
+            .iter()
+            .map(|cue| (cue.album_name.as_str(), cue.removed))
+            .collect()
+    }
+
+    #[test]
+    fn changes_since_has_only_changed_albums() {
+        let mut tree = CountTree::new();
+        set(&mut tree, "Wedding", &["a.jpg"]);
+        set(&mut tree, "Party", &["a.jpg"]);
+        let since = tree.snapshot().unwrap().sequence;
+        set(&mut tree, "Wedding", &["a.jpg", "b.jpg"]);
+        set(&mut tree, "Wedding", &["a.jpg", "b.jpg", "c.jpg"]);
+
+        let catchup = tree.changes_since(tree.nonce, since).unwrap();
+
+        assert!(catchup.partial);
+        assert_eq!(catchup.sequence, since + 2);
+        assert_eq!(albums(&catchup), vec![("Wedding", false)]);
+        assert_eq!(catchup.albums[0].count.raw, 3);
+        assert_eq!(catchup.albums[0].sequence, since + 2);
+    }
+
+    #[test]
+    fn changes_since_reports_removed_albums() {
+        let mut tree = CountTree::new();
+        set(&mut tree, "Wedding", &["a.jpg"]);
+        let since = tree.snapshot().unwrap().sequence;
+        set(&mut tree, "Party", &["a.jpg"]);
+        set(&mut tree, "Wedding", &[]);
+
+        let catchup = tree.changes_since(tree.nonce, since).unwrap();
+
+        assert_eq!(albums(&catchup), vec![("Party", false), ("Wedding", true)]);
+        assert!(tree
+            .changes_since(tree.nonce, tree.sequence)
+            .unwrap()
+            .albums
+            .is_empty());
+    }
+
+    #[test]
+    fn changes_since_falls_back_to_a_snapshot() {
+        let mut tree = CountTree::new();
+        set(&mut tree, "Wedding", &["a.jpg"]);
+        let sequence = tree.sequence;
+
+        // From another run of the server
+        assert!(
+            !tree
+                .changes_since(tree.nonce + 1, sequence)
+                .unwrap()
+                .partial
+        );
+        // From the future
+        assert!(
+            !tree
+                .changes_since(tree.nonce, sequence + 1)
+                .unwrap()
+                .partial
+        );
+
+        // Older than the change log
+        for i in 0..CHANGE_LOG_SIZE {
+            set(&mut tree, "Party", &[&format!("{}.jpg", i)]);
+        }
+        assert!(tree.changes_since(tree.nonce, sequence).unwrap().partial);
+        set(&mut tree, "Party", &["last.jpg"]);
+        let catchup = tree.changes_since(tree.nonce, sequence).unwrap();
+        assert!(!catchup.partial);
+        assert_eq!(catchup.albums.len(), 2);
+    }
+
+    #[test]
+    fn removed_albums_keep_their_totals_until_they_expire() {
+        let mut tree = CountTree::new();
+        let retention = Duration::from_secs(60);
+        set(&mut tree, "Wedding", &["a.jpg", "b.jpg"]);
+        set(&mut tree, "Wedding", &[]);
+        set(&mut tree, "Party", &["a.jpg", "b.jpg"]);
+        set(&mut tree, "Party", &[]);
+
+        // Wedding comes back within the retention, with one file deleted
+        assert_eq!(tree.expire(retention, SystemTime::now()), 0);
+        let cue = set(&mut tree, "Wedding", &["a.jpg"]).unwrap();
+        assert_eq!(cue.count.total, 2);
+
+        // Party doesn't
+        assert_eq!(tree.expire(retention, SystemTime::now() + retention), 1);
+        let cue = set(&mut tree, "Party", &["a.jpg"]).unwrap();
+        assert_eq!(cue.count.total, 1);
+    }
+
+    #[test]
+    fn unknown_empty_albums_are_ignored() {
+        let mut tree = CountTree::new();
+
+        assert!(set(&mut tree, "Wedding", &[]).is_none());
+        assert_eq!(tree.sequence, 0);
+    }
 }
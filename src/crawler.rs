@@ -1,21 +1,36 @@
-use super::directory_layout::{AlbumType, DirectoryLayout, PathComponent};
-use super::{DirectoryUpdateEvent, GroupType, SetEvent};
-use crate::worker::{Worker, WorkerResult};
+use super::directory_layout::{DirectoryLayout, DirectoryPath};
+use super::{DirectoryUpdateEvent, GroupType, OfflineEvent, SetEvent};
+use crate::filesystem::FileSystem;
+use crate::metrics::Histogram;
+use crate::resolver::{resolve, ResolvedAlbum};
+use crate::stability::StabilityFilter;
+use crate::supervisor::SupervisedSender;
+use crate::worker::{Shutdown, Worker, WorkerError, WorkerResult};
 use derive_new::new;
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
+use edit_count::protocol;
+use failure::Error;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
 #[derive(Debug, new)]
 pub struct Crawler {
+    fs: Arc<dyn FileSystem>,
+    root_retry_interval: u64,
+    stability: StabilityFilter,
     layout: DirectoryLayout,
-    due_tx: Sender<DirectoryUpdateEvent>,
+    due_tx: SupervisedSender<DirectoryUpdateEvent>,
+    /// Shared with the previous Crawler, so the status survives a restart
+    status: CrawlerHandle,
 }
 
 impl Worker for Crawler {
     type W = Crawler;
     const NAME: &'static str = "Crawler";
 
-    fn work(self) -> WorkerResult {
+    fn work(self, shutdown: Shutdown) -> WorkerResult {
         let mut paths = Vec::new();
         for path in self.layout.raw_dirs.iter() {
             paths.push((GroupType::Raw, path));
@@ -24,204 +39,249 @@ impl Worker for Crawler {
             paths.push((GroupType::Render, path));
         }
 
-        for (path_type, path) in paths {
-            let mut path_cache = PathBuf::new();
-            let mut groups: Vec<NamedPath> = Vec::new();
-            let mut filled_groups: Vec<(NamedPath, Vec<NamedPath>)> = Vec::new();
-            for component in path {
-                match component {
-                    PathComponent::Group(group) => {
-                        // Process group
-
-                        let mut new_groups: Vec<NamedPath> = Vec::new();
-                        if groups.is_empty() {
-                            for entry in deep_list(&path_cache, group.depth - 1, group.depth) {
-                                new_groups.push(NamedPath {
-                                    name: entry.to_string_lossy().into_owned(),
-                                    path: path_cache.join(entry),
-                                });
-                            }
-                        } else {
-                            for old_group in groups {
-                                let search_path = old_group.path.join(&path_cache);
-                                for entry in deep_list(&search_path, group.depth - 1, group.depth) {
-                                    new_groups.push(NamedPath {
-                                        name: format!(
-                                            "{}:{}",
-                                            old_group.name,
-                                            entry.to_string_lossy().into_owned(),
-                                        ),
-                                        path: search_path.join(entry),
-                                    });
-                                }
-                            }
-                        }
-
-                        groups = new_groups;
-                        path_cache = PathBuf::new();
-                    }
-
-                    PathComponent::Album(album) => {
-                        // Process album
-
-                        // If the groups Vec is empty, then no groups were found.
-                        // Put all albums into a virtual group that will be hidden in the web view.
-                        if groups.is_empty() {
-                            groups = vec![NamedPath {
-                                name: String::from("%default%"),
-                                path: PathBuf::new(),
-                            }];
-                        }
-
-                        for group in groups {
-                            // Determine search depth
-                            let (filter_depth, search_depth) = match &album.tipe {
-                                AlbumType::Single => (0, 1),
-                                AlbumType::Depth => (album.min - 1, album.min),
-                                AlbumType::Range => (album.min - 1, album.max),
-                            };
-
-                            let group_path = group.path.join(&path_cache);
-                            let entries = deep_list(&group_path, filter_depth, search_depth);
-                            let mut album_cache = Vec::with_capacity(entries.len());
-
-                            for entry in entries {
-                                album_cache.push(NamedPath {
-                                    name: entry.to_string_lossy().into_owned(),
-                                    path: group_path.join(entry),
-                                });
-                            }
-
-                            filled_groups.push((group, album_cache));
-                        }
-
-                        groups = Vec::new();
-                        path_cache = PathBuf::new();
-                    }
-
-                    PathComponent::Dir(dir) => {
-                        // Process path
-
-                        path_cache.push(dir);
-                    }
+        // Roots that aren't mounted yet, or have been unmounted, are crawled once they return
+        let mut online: HashMap<usize, bool> = HashMap::new();
+        // Albums with partially written files, to be read again
+        let mut unstable: Vec<(GroupType, ResolvedAlbum)> = Vec::new();
+        loop {
+            for (i, (path_type, path)) in paths.iter().enumerate() {
+                let root = path.root();
+                let present = self.fs.is_mounted(&root);
+                let was_present = online.insert(i, present);
+                if was_present == Some(present) {
+                    continue;
                 }
-            }
 
-            if !path_cache.as_os_str().is_empty() {
-                for group in &mut filled_groups {
-                    for album in &mut group.1 {
-                        album.path.push(&path_cache);
+                if present {
+                    let started = Instant::now();
+                    self.crawl(*path_type, &path.path, &mut unstable)?;
+                    self.status.crawled(started.elapsed())?;
+                    info!(path = ?root, elapsed = ?started.elapsed(), "Crawled layout root");
+                } else {
+                    warn!(path = ?root, "Layout root is missing or empty, waiting for it");
+
+                    // Keep the last known counts for albums that were on this root,
+                    // including those the Listener found after the crawl
+                    if was_present == Some(true) {
+                        let event = OfflineEvent {
+                            tipe: *path_type,
+                            root,
+                            since: SystemTime::now(),
+                        };
+                        self.due_tx.send(DirectoryUpdateEvent::Offline(event))?;
                     }
                 }
             }
 
-            for (group, albums) in filled_groups {
-                for NamedPath { name, path } in albums {
-                    let mut files = Vec::new();
-                    let contents = path.read_dir();
+            // Partially written files are read again once they have had time to settle
+            let mut wait = Duration::from_millis(self.root_retry_interval);
+            if !unstable.is_empty() {
+                wait = wait.min(self.stability.min_age()?);
+            }
+            if shutdown.wait_timeout(wait) {
+                return Ok(());
+            }
 
-                    if contents.is_err() {
-                        println!("Error reading folder {:?}: {:#?}", path, contents);
-                    }
+            for (path_type, album) in mem::take(&mut unstable) {
+                self.send_album(path_type, album, &mut unstable)?;
+            }
+        }
+    }
+}
 
-                    for file in contents.unwrap() {
-                        match file {
-                            Ok(f) => files.push(f.file_name()),
-                            Err(e) => println!("Error reading file: {:#?}", e),
-                        }
-                    }
+impl Crawler {
+    /// Send the contents of every album on `path`.
+    fn crawl(
+        &self,
+        path_type: GroupType,
+        path: &DirectoryPath,
+        unstable: &mut Vec<(GroupType, ResolvedAlbum)>,
+    ) -> Result<(), Error> {
+        for album in resolve(&*self.fs, path).albums {
+            self.send_album(path_type, album, unstable)?;
+        }
 
-                    let event = SetEvent {
-                        group_name: group.name.to_string(),
-                        album_name: name,
-                        tipe: path_type,
-                        files,
-                    };
+        Ok(())
+    }
 
-                    self.due_tx.send(DirectoryUpdateEvent::Set(event))?;
-                }
-            }
+    /// Send the stable contents of `album`, if it can be read.
+    /// Albums with partially written files are added to `unstable`.
+    fn send_album(
+        &self,
+        path_type: GroupType,
+        album: ResolvedAlbum,
+        unstable: &mut Vec<(GroupType, ResolvedAlbum)>,
+    ) -> Result<(), Error> {
+        let contents = match self.stability.read_album(&*self.fs, &album)? {
+            Some(contents) => contents,
+            None => return Ok(()),
+        };
+
+        let event = SetEvent {
+            group_name: album.group_name.clone(),
+            album_name: album.album_name.clone(),
+            path: album.path.clone(),
+            tipe: path_type,
+            files: contents.files,
+            events: 0,
+        };
+        self.due_tx.send(DirectoryUpdateEvent::Set(event))?;
+
+        if contents.unstable {
+            unstable.push((path_type, album));
         }
 
         Ok(())
     }
 }
 
-#[derive(Debug)]
-struct NamedPath {
-    pub name: String,
-    pub path: PathBuf,
+#[derive(Debug, Default, Clone)]
+pub struct CrawlerStatus {
+    /// When the last crawl of a layout path finished
+    pub last_crawl: Option<SystemTime>,
+    pub last_crawl_duration: Option<Duration>,
+    pub crawl_durations: Histogram,
 }
 
-fn deep_list(base: &PathBuf, filter_depth: usize, search_depth: usize) -> Vec<PathBuf> {
-    let mut final_dirs = Vec::new();
-    let mut search_paths: Vec<PathBuf> = vec![base.into()];
-
-    for i in 0..search_depth {
-        let mut new_search_paths: Vec<PathBuf> = Vec::new();
-        for path in search_paths {
-            // The loop is bootstrapped with the base path.
-            // All other entries in new_dirs will be relative and must be joined to the base path.
-            let dirs = if i == 0 {
-                path.read_dir()
-            } else {
-                base.join(&path).read_dir()
-            };
-
-            // Warn and skip if listing failed
-            let dirs = match dirs {
-                Ok(d) => d,
-                Err(e) => {
-                    println!("{:?}", e);
-                    continue;
-                }
-            };
-
-            for entry in dirs {
-                // Warn and skip errors
-                let entry = match entry {
-                    Ok(entry) => entry,
-                    Err(e) => {
-                        println!("{:?}", e);
-                        continue;
-                    }
-                };
+impl From<CrawlerStatus> for protocol::CrawlerStatus {
+    fn from(status: CrawlerStatus) -> protocol::CrawlerStatus {
+        protocol::CrawlerStatus {
+            last_crawl: status
+                .last_crawl
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            last_crawl_duration_ms: status.last_crawl_duration.map(|d| d.as_millis() as u64),
+        }
+    }
+}
 
-                let file_type = match entry.file_type() {
-                    Ok(file_type) => file_type,
-                    Err(e) => {
-                        println!("Couldn't get file type for {:?}: {:#?}", entry.path(), e);
-                        continue;
-                    }
-                };
+#[derive(Debug, Default, Clone)]
+pub struct CrawlerHandle(Arc<Mutex<CrawlerStatus>>);
 
-                // Ignore files
-                if file_type.is_dir() {
-                    let name: PathBuf;
+impl CrawlerHandle {
+    pub fn status(&self) -> Result<CrawlerStatus, Error> {
+        Ok(self.lock()?.clone())
+    }
 
-                    // Don't include base path
-                    if i == 0 {
-                        name = entry.file_name().into();
-                    } else {
-                        name = path.join(entry.file_name())
-                    }
-                    new_search_paths.push(name);
-                }
-            }
+    fn crawled(&self, duration: Duration) -> Result<(), Error> {
+        let mut status = self.lock()?;
+        status.last_crawl = Some(SystemTime::now());
+        status.last_crawl_duration = Some(duration);
+        status.crawl_durations.observe(duration);
+
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, CrawlerStatus>, Error> {
+        Ok(self.0.lock().or(Err(WorkerError::new_resource_poisoned(
+            "Crawler.status".to_string(),
+        )))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory_layout::{parse_path, LayoutPath};
+    use crate::filesystem::memory::MemoryFileSystem;
+    use crate::settings::{StabilitySettings, WatcherMode};
+    use crate::worker::WorkerHandle;
+    use std::path::PathBuf;
+    use std::sync::mpsc::Receiver;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn start(fs: &MemoryFileSystem) -> (WorkerHandle, Receiver<DirectoryUpdateEvent>) {
+        let layout = DirectoryLayout {
+            raw_dirs: vec![LayoutPath {
+                path: parse_path("/photos/[G]/[A]").unwrap(),
+                watcher: WatcherMode::Native,
+            }],
+            render_dirs: Vec::new(),
+        };
+        let stability = StabilityFilter::new(StabilitySettings {
+            min_age: 0,
+            ..StabilitySettings::default()
+        });
+        let due_tx = SupervisedSender::new();
+        let rx = due_tx.take_receiver().unwrap();
+        let crawler = Crawler::new(
+            Arc::new(fs.clone()),
+            20,
+            stability,
+            layout,
+            due_tx,
+            CrawlerHandle::default(),
+        );
+
+        (crawler.start().unwrap(), rx)
+    }
+
+    fn next_set(rx: &Receiver<DirectoryUpdateEvent>) -> SetEvent {
+        match rx.recv_timeout(TIMEOUT).unwrap() {
+            DirectoryUpdateEvent::Set(event) => event,
+            event => panic!("Expected a Set, got {:?}", event),
         }
+    }
 
-        if new_search_paths.is_empty() {
-            break;
+    #[test]
+    fn unmounted_root_goes_offline_and_is_crawled_when_it_returns() {
+        let fs = MemoryFileSystem::new();
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        fs.write_file("/photos/2024/Wedding/b.jpg", 10).unwrap();
+        let (crawler, rx) = start(&fs);
+
+        let event = next_set(&rx);
+        assert_eq!(event.album_name, "Wedding");
+        assert_eq!(event.files.len(), 2);
+        assert_eq!(event.events, 0);
+
+        fs.remove("/photos").unwrap();
+        match rx.recv_timeout(TIMEOUT).unwrap() {
+            DirectoryUpdateEvent::Offline(event) => {
+                assert_eq!(event.tipe, GroupType::Raw);
+                assert_eq!(event.root, PathBuf::from("/photos"));
+            }
+            event => panic!("Expected Offline, got {:?}", event),
         }
 
-        if i >= filter_depth {
-            for path in new_search_paths.iter() {
-                final_dirs.push(path.clone());
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        let event = next_set(&rx);
+        assert_eq!(event.album_name, "Wedding");
+        assert_eq!(event.files.len(), 1);
+
+        crawler.stop().unwrap();
+    }
+
+    #[test]
+    fn empty_root_goes_offline() {
+        let fs = MemoryFileSystem::new();
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        let (crawler, rx) = start(&fs);
+        next_set(&rx);
+
+        // The mount point is left behind
+        fs.remove("/photos/2024").unwrap();
+        match rx.recv_timeout(TIMEOUT).unwrap() {
+            DirectoryUpdateEvent::Offline(event) => {
+                assert_eq!(event.root, PathBuf::from("/photos"))
             }
+            event => panic!("Expected Offline, got {:?}", event),
         }
 
-        search_paths = new_search_paths;
+        crawler.stop().unwrap();
     }
 
-    final_dirs
+    #[test]
+    fn missing_root_is_not_reported_offline() {
+        let fs = MemoryFileSystem::new();
+        let (crawler, rx) = start(&fs);
+
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        assert_eq!(next_set(&rx).album_name, "Wedding");
+
+        crawler.stop().unwrap();
+    }
 }
@@ -1,3 +1,4 @@
+use crate::settings::WatcherMode;
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
 use serde::de::{self, Deserialize, Deserializer};
@@ -22,7 +23,7 @@ impl LayoutError {
         message: &str,
     ) -> LayoutError {
         LayoutError {
-            path: path.and_then(|p| Some(p.to_owned())),
+            path: path.map(|p| p.to_owned()),
             start,
             length,
             message: String::from(message),
@@ -50,61 +51,104 @@ impl error::Error for LayoutError {
         "unable to parse layout"
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
 pub struct DirectoryLayout {
     #[serde(deserialize_with = "deserialize_dirs")]
-    pub raw_dirs: Vec<DirectoryPath>,
+    pub raw_dirs: Vec<LayoutPath>,
     #[serde(deserialize_with = "deserialize_dirs")]
-    pub render_dirs: Vec<DirectoryPath>,
+    pub render_dirs: Vec<LayoutPath>,
+}
+
+/// A parsed `raw_dirs` or `render_dirs` entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutPath {
+    pub path: DirectoryPath,
+    pub watcher: WatcherMode,
+}
+
+impl LayoutPath {
+    /// The literal directory before the first operator.
+    pub fn root(&self) -> PathBuf {
+        let mut root = PathBuf::new();
+        for component in &self.path {
+            match component {
+                PathComponent::Dir(dir) => root.push(dir),
+                _ => break,
+            }
+        }
+
+        root
+    }
 }
 
 pub type DirectoryPath = Vec<PathComponent>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PathComponent {
     Album(Album),
     Group(Group),
     Dir(PathBuf),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Group {
     pub depth: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Album {
     pub min: usize,
     pub max: usize,
     pub tipe: AlbumType,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AlbumType {
     Single,
     Depth,
     Range,
 }
 
-fn deserialize_dirs<'de, D>(deserializer: D) -> Result<Vec<DirectoryPath>, D::Error>
+/// Layout entries are either a bare path string,
+/// or a table with a `path` and optional `watcher` settings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UnparsedPath {
+    Plain(String),
+    Configured {
+        path: String,
+        #[serde(default)]
+        watcher: WatcherMode,
+    },
+}
+
+fn deserialize_dirs<'de, D>(deserializer: D) -> Result<Vec<LayoutPath>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let unparsed: Vec<String> = Deserialize::deserialize(deserializer)?;
-    let mut parsed: Vec<DirectoryPath> = Vec::with_capacity(unparsed.len());
+    let unparsed: Vec<UnparsedPath> = Deserialize::deserialize(deserializer)?;
+    let mut parsed: Vec<LayoutPath> = Vec::with_capacity(unparsed.len());
     for p in unparsed {
-        parsed.push(parse_path(&p).map_err(de::Error::custom)?);
+        let (path, watcher) = match p {
+            UnparsedPath::Plain(path) => (path, WatcherMode::default()),
+            UnparsedPath::Configured { path, watcher } => (path, watcher),
+        };
+
+        parsed.push(LayoutPath {
+            path: parse_path(&path).map_err(de::Error::custom)?,
+            watcher,
+        });
     }
 
     Ok(parsed)
 }
 
-fn parse_path(s: &str) -> Result<DirectoryPath, LayoutError> {
+pub fn parse_path(s: &str) -> Result<DirectoryPath, LayoutError> {
     lazy_static! {
         static ref ALBUM_PATTERN: Regex =
             Regex::new(r"\[A(?P<min>\d+)?(?P<dot>.(?P<max>\d+)?)?\]").unwrap();
@@ -117,9 +161,15 @@ fn parse_path(s: &str) -> Result<DirectoryPath, LayoutError> {
     let mut index = 0;
     let mut album = 0;
 
-    for op_string in s.split(|c| c == '\\' || c == '/') {
+    for op_string in s.split(['\\', '/']) {
         let component: PathComponent;
 
+        // Skip repeated and trailing separators, but keep the root of absolute paths
+        if op_string.is_empty() && !(path.is_empty() && cache_empty) {
+            index += 1;
+            continue;
+        }
+
         // Operator
         if op_string.starts_with('[') && op_string.ends_with(']') {
             // Dump path cache
@@ -131,34 +181,23 @@ fn parse_path(s: &str) -> Result<DirectoryPath, LayoutError> {
 
             // Album operator
             if let Some(captures) = ALBUM_PATTERN.captures(op_string) {
-                let min: usize = match get_key(&captures, "min").parse() {
-                    Ok(i) => i,
-                    Err(_) => 1,
-                };
-
-                let max: usize = match get_key(&captures, "max").parse() {
-                    Ok(i) => i,
-                    Err(_) => usize::max_value(),
-                };
+                let min: usize = get_key(&captures, "min").parse().unwrap_or(1);
+                let max: usize = get_key(&captures, "max").parse().unwrap_or(usize::MAX);
 
-                let tipe: AlbumType;
-                if get_key(&captures, "dot") == "." {
-                    tipe = AlbumType::Range;
+                let tipe = if get_key(&captures, "dot") == "." {
+                    AlbumType::Range
                 } else if min != 1 {
-                    tipe = AlbumType::Depth;
+                    AlbumType::Depth
                 } else {
-                    tipe = AlbumType::Single;
-                }
+                    AlbumType::Single
+                };
 
                 component = PathComponent::Album(Album { min, max, tipe });
                 album += 1;
 
             // Group operator
             } else if let Some(captures) = GROUP_PATTERN.captures(op_string) {
-                let depth: usize = match get_key(&captures, "depth").parse() {
-                    Ok(i) => i,
-                    Err(_) => 1,
-                };
+                let depth: usize = get_key(&captures, "depth").parse().unwrap_or(1);
 
                 component = PathComponent::Group(Group { depth });
 
@@ -192,19 +231,24 @@ fn parse_path(s: &str) -> Result<DirectoryPath, LayoutError> {
         index += op_string.len() + 1;
     }
 
+    // Directories after the last operator, such as the `Edits` in `[G]/[A]/Edits`
+    if !cache_empty {
+        path.push(PathComponent::Dir(path_cache));
+    }
+
     if album > 1 {
         return Err(LayoutError::new(
-            Some(&s),
+            Some(s),
             0,
             None,
-            "Missing Album operator.",
+            "Multiple Album operators.\nOnly one Album operator is allowed.",
         ));
     } else if album < 1 {
         return Err(LayoutError::new(
-            Some(&s),
+            Some(s),
             0,
             None,
-            "Multiple Album operators.\nOnly one Album operator is allowed.",
+            "Missing Album operator.",
         ));
     }
 
@@ -0,0 +1,137 @@
+// Only constructed by scripted scenarios, not by the server itself
+#[cfg(any(test, feature = "memory-fs"))]
+#[allow(dead_code)]
+pub mod memory;
+
+use failure::Error;
+use notify::{watcher, DebouncedEvent, PollWatcher, RecursiveMode, Watcher};
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// The filesystem operations the crawler and listener depend on.
+///
+/// Implemented by `RealFileSystem` for the host disk and by
+/// `memory::MemoryFileSystem` for scripted, in-memory directory trees.
+pub trait FileSystem: Debug + Send + Sync {
+    /// List the direct children of `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+    /// Whether `path` exists and is a directory.
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// Whether the layout root at `path` is mounted, a directory with something in it.
+    /// An unplugged drive can leave its mount point behind as an empty directory,
+    /// which mustn't be read as every album on the drive being deleted.
+    fn is_mounted(&self, path: &Path) -> bool {
+        self.read_dir(path).is_ok_and(|entries| !entries.is_empty())
+    }
+
+    /// Create a watcher that reports changes to `watcher_tx`.
+    /// `delay` is the debounce window, where the backend supports one.
+    fn watcher(
+        &self,
+        watcher_tx: Sender<DebouncedEvent>,
+        delay: Duration,
+    ) -> Result<Box<dyn FsWatcher>, Error>;
+
+    /// Create a watcher that scans its watched paths every `interval`,
+    /// for filesystems where native events are unreliable.
+    fn poll_watcher(
+        &self,
+        watcher_tx: Sender<DebouncedEvent>,
+        interval: Duration,
+    ) -> Result<Box<dyn FsWatcher>, Error>;
+}
+
+/// A handle for registering paths with a filesystem watcher.
+pub trait FsWatcher: Send {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()>;
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()>;
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub is_dir: bool,
+    /// Size in bytes, 0 for directories
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Filesystem backend using `std::fs` and `notify`'s native and polling watchers.
+#[derive(Debug, Default)]
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        for entry in path.read_dir()? {
+            // Warn and skip errors
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(path = ?path, error = %e, "Error reading directory entry");
+                    continue;
+                }
+            };
+
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!(path = ?entry.path(), error = %e, "Couldn't get metadata");
+                    continue;
+                }
+            };
+
+            entries.push(DirEntry {
+                name: entry.file_name(),
+                is_dir: metadata.is_dir(),
+                size: if metadata.is_dir() { 0 } else { metadata.len() },
+                modified: metadata.modified().ok(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn watcher(
+        &self,
+        watcher_tx: Sender<DebouncedEvent>,
+        delay: Duration,
+    ) -> Result<Box<dyn FsWatcher>, Error> {
+        Ok(Box::new(NotifyWatcher(watcher(watcher_tx, delay)?)))
+    }
+
+    fn poll_watcher(
+        &self,
+        watcher_tx: Sender<DebouncedEvent>,
+        interval: Duration,
+    ) -> Result<Box<dyn FsWatcher>, Error> {
+        Ok(Box::new(NotifyWatcher(PollWatcher::new(
+            watcher_tx, interval,
+        )?)))
+    }
+}
+
+/// Adapts any `notify::Watcher` to `FsWatcher`.
+pub struct NotifyWatcher<W: Watcher>(W);
+
+impl<W: Watcher + Send> FsWatcher for NotifyWatcher<W> {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+        self.0.watch(path, mode)
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        self.0.unwatch(path)
+    }
+}
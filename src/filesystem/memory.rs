@@ -0,0 +1,381 @@
+use super::{DirEntry, FileSystem, FsWatcher};
+use failure::Error;
+use notify::{DebouncedEvent, RecursiveMode};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+/// An in-memory directory tree.
+///
+/// Mutations are reported to any `MemoryWatcher` watching the affected paths
+/// as the same `DebouncedEvent`s the native watcher produces,
+/// so layout and counting scenarios can be scripted without touching the disk.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileSystem(Arc<Mutex<MemoryTree>>);
+
+#[derive(Debug, Default)]
+struct MemoryTree {
+    nodes: BTreeMap<PathBuf, NodeKind>,
+    watches: HashMap<usize, WatchSet>,
+    next_watch_id: usize,
+    /// How many paths native watchers can watch, like Linux's `fs.inotify.max_user_watches`
+    native_watch_limit: Option<usize>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum NodeKind {
+    Dir,
+    File { size: u64, modified: SystemTime },
+}
+
+#[derive(Debug)]
+struct WatchSet {
+    native: bool,
+    watcher_tx: Sender<DebouncedEvent>,
+    paths: HashMap<PathBuf, RecursiveMode>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> MemoryFileSystem {
+        MemoryFileSystem::default()
+    }
+
+    /// Create a directory and any missing parents.
+    pub fn create_dir_all<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut tree = self.lock()?;
+        tree.create_dirs(path.as_ref())
+    }
+
+    /// Create or overwrite a file of `size` bytes, creating any missing parent directories.
+    /// The file's modification time is set to now.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P, size: u64) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tree = self.lock()?;
+        if let Some(parent) = path.parent() {
+            tree.create_dirs(parent)?;
+        }
+
+        let file = NodeKind::File {
+            size,
+            modified: SystemTime::now(),
+        };
+        match tree.nodes.insert(path.to_path_buf(), file) {
+            None => tree.notify(DebouncedEvent::Create(path.to_path_buf())),
+            Some(NodeKind::File { .. }) => tree.notify(DebouncedEvent::Write(path.to_path_buf())),
+            Some(NodeKind::Dir) => {
+                tree.nodes.insert(path.to_path_buf(), NodeKind::Dir);
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} is a directory", path),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Set a file's modification time, without reporting a change.
+    pub fn set_modified<P: AsRef<Path>>(&self, path: P, time: SystemTime) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tree = self.lock()?;
+        match tree.nodes.get_mut(path) {
+            Some(NodeKind::File { modified, .. }) => {
+                *modified = time;
+                Ok(())
+            }
+            _ => Err(not_found(path)),
+        }
+    }
+
+    /// Remove a file, or a directory and everything below it.
+    pub fn remove<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tree = self.lock()?;
+        if !tree.nodes.contains_key(path) {
+            return Err(not_found(path));
+        }
+
+        // Remove children before their parents, as the native watcher reports them
+        let mut removed = tree.subtree(path);
+        removed.reverse();
+        for removed_path in removed {
+            tree.nodes.remove(&removed_path);
+            tree.notify(DebouncedEvent::Remove(removed_path));
+        }
+
+        Ok(())
+    }
+
+    /// Move a file or directory, along with everything below it.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, from: P, to: Q) -> io::Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let mut tree = self.lock()?;
+        if !tree.nodes.contains_key(from) {
+            return Err(not_found(from));
+        }
+        if tree.nodes.contains_key(to) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", to),
+            ));
+        }
+        if let Some(parent) = to.parent() {
+            tree.create_dirs(parent)?;
+        }
+
+        for old_path in tree.subtree(from) {
+            let kind = tree
+                .nodes
+                .remove(&old_path)
+                .ok_or_else(|| not_found(&old_path))?;
+            let relative = old_path.strip_prefix(from).unwrap_or(&old_path);
+            tree.nodes.insert(to.join(relative), kind);
+        }
+
+        tree.notify(DebouncedEvent::Rename(from.to_path_buf(), to.to_path_buf()));
+        Ok(())
+    }
+
+    /// Send a `Rescan` to every watcher, as the native watcher does when it loses events.
+    pub fn rescan(&self) -> io::Result<()> {
+        let tree = self.lock()?;
+        for watch in tree.watches.values() {
+            let _ = watch.watcher_tx.send(DebouncedEvent::Rescan);
+        }
+
+        Ok(())
+    }
+
+    /// Fail native watches past the first `limit` paths, with `ENOSPC` like inotify.
+    /// Polling watchers have no limit.
+    pub fn set_native_watch_limit(&self, limit: usize) -> io::Result<()> {
+        self.lock()?.native_watch_limit = Some(limit);
+
+        Ok(())
+    }
+
+    fn add_watcher(
+        &self,
+        watcher_tx: Sender<DebouncedEvent>,
+        native: bool,
+    ) -> Result<Box<dyn FsWatcher>, Error> {
+        let mut tree = self.lock()?;
+        let id = tree.next_watch_id;
+        tree.next_watch_id += 1;
+        tree.watches.insert(
+            id,
+            WatchSet {
+                native,
+                watcher_tx,
+                paths: HashMap::new(),
+            },
+        );
+
+        Ok(Box::new(MemoryWatcher {
+            id,
+            fs: self.clone(),
+        }))
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, MemoryTree>> {
+        self.0
+            .lock()
+            .map_err(|_| io::Error::other("MemoryFileSystem was poisoned"))
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let tree = self.lock()?;
+        if !tree.is_dir(path) {
+            return Err(not_found(path));
+        }
+
+        let entries = tree
+            .nodes
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(path))
+            .filter_map(|(p, kind)| {
+                let (is_dir, size, modified) = match *kind {
+                    NodeKind::Dir => (true, 0, None),
+                    NodeKind::File { size, modified } => (false, size, Some(modified)),
+                };
+
+                p.file_name().map(|name| DirEntry {
+                    name: name.to_os_string(),
+                    is_dir,
+                    size,
+                    modified,
+                })
+            })
+            .collect();
+
+        Ok(entries)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.lock().map(|tree| tree.is_dir(path)).unwrap_or(false)
+    }
+
+    /// Memory watchers deliver events immediately, so `delay` is ignored.
+    fn watcher(
+        &self,
+        watcher_tx: Sender<DebouncedEvent>,
+        _delay: Duration,
+    ) -> Result<Box<dyn FsWatcher>, Error> {
+        self.add_watcher(watcher_tx, true)
+    }
+
+    /// Memory watchers see every change, so polling behaves like native watching.
+    fn poll_watcher(
+        &self,
+        watcher_tx: Sender<DebouncedEvent>,
+        _interval: Duration,
+    ) -> Result<Box<dyn FsWatcher>, Error> {
+        self.add_watcher(watcher_tx, false)
+    }
+}
+
+impl MemoryTree {
+    fn is_dir(&self, path: &Path) -> bool {
+        // Filesystem roots always exist
+        path.parent().is_none() || self.nodes.get(path) == Some(&NodeKind::Dir)
+    }
+
+    fn create_dirs(&mut self, path: &Path) -> io::Result<()> {
+        let mut missing = Vec::new();
+        for ancestor in path.ancestors().filter(|a| a.parent().is_some()) {
+            match self.nodes.get(ancestor) {
+                Some(NodeKind::Dir) => break,
+                Some(NodeKind::File { .. }) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{:?} is a file", ancestor),
+                    ));
+                }
+                None => missing.push(ancestor.to_path_buf()),
+            }
+        }
+
+        for dir in missing.into_iter().rev() {
+            self.nodes.insert(dir.clone(), NodeKind::Dir);
+            self.notify(DebouncedEvent::Create(dir));
+        }
+
+        Ok(())
+    }
+
+    /// `path` and all paths below it, parents first.
+    fn subtree(&self, path: &Path) -> Vec<PathBuf> {
+        self.nodes
+            .range(path.to_path_buf()..)
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    fn notify(&self, event: DebouncedEvent) {
+        for watch in self.watches.values() {
+            let matched = match &event {
+                DebouncedEvent::Rename(from, to) => watch.covers(from) || watch.covers(to),
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Remove(path) => watch.covers(path),
+                _ => true,
+            };
+
+            if matched {
+                // A hung up watcher is cleaned up when it is dropped
+                let _ = watch.watcher_tx.send(copy_event(&event));
+            }
+        }
+    }
+}
+
+impl WatchSet {
+    fn covers(&self, path: &Path) -> bool {
+        self.paths.iter().any(|(watched, mode)| match mode {
+            RecursiveMode::Recursive => path.starts_with(watched),
+            RecursiveMode::NonRecursive => path == watched || path.parent() == Some(watched),
+        })
+    }
+}
+
+/// Watcher registered with a `MemoryFileSystem`.
+pub struct MemoryWatcher {
+    id: usize,
+    fs: MemoryFileSystem,
+}
+
+impl FsWatcher for MemoryWatcher {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+        let mut tree = self.fs.lock()?;
+        if !tree.is_dir(path) && !tree.nodes.contains_key(path) {
+            return Err(notify::Error::PathNotFound);
+        }
+
+        let native_watches: usize = tree
+            .watches
+            .values()
+            .filter(|w| w.native)
+            .map(|w| w.paths.len())
+            .sum();
+        let is_native = tree.watches.get(&self.id).is_some_and(|w| w.native);
+        if is_native
+            && tree
+                .native_watch_limit
+                .is_some_and(|limit| native_watches >= limit)
+        {
+            const ENOSPC: i32 = 28;
+            return Err(notify::Error::Io(io::Error::from_raw_os_error(ENOSPC)));
+        }
+
+        if let Some(watch) = tree.watches.get_mut(&self.id) {
+            watch.paths.insert(path.to_path_buf(), mode);
+        }
+
+        Ok(())
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        let mut tree = self.fs.lock()?;
+        match tree
+            .watches
+            .get_mut(&self.id)
+            .and_then(|w| w.paths.remove(path))
+        {
+            Some(_) => Ok(()),
+            None => Err(notify::Error::WatchNotFound),
+        }
+    }
+}
+
+impl Drop for MemoryWatcher {
+    fn drop(&mut self) {
+        if let Ok(mut tree) = self.fs.lock() {
+            tree.watches.remove(&self.id);
+        }
+    }
+}
+
+/// `DebouncedEvent` isn't `Clone`, but every event a `MemoryFileSystem` emits can be copied.
+fn copy_event(event: &DebouncedEvent) -> DebouncedEvent {
+    match event {
+        DebouncedEvent::Create(path) => DebouncedEvent::Create(path.clone()),
+        DebouncedEvent::Write(path) => DebouncedEvent::Write(path.clone()),
+        DebouncedEvent::Remove(path) => DebouncedEvent::Remove(path.clone()),
+        DebouncedEvent::Rename(from, to) => DebouncedEvent::Rename(from.clone(), to.clone()),
+        _ => DebouncedEvent::Rescan,
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{:?} does not exist", path),
+    )
+}
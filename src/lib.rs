@@ -0,0 +1,3 @@
+//! Types shared between the edit_count server and its clients.
+
+pub mod protocol;
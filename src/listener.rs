@@ -1,96 +1,271 @@
-use super::directory_layout::{DirectoryLayout, PathComponent};
-use crate::worker::{Worker, WorkerResult};
+use super::directory_layout::DirectoryLayout;
+use crate::coalescer::{CoalescedAlbum, Coalescer};
+use crate::filesystem::{FileSystem, FsWatcher};
+use crate::resolver::{resolve, ResolvedAlbum};
+use crate::settings::WatcherMode;
+use crate::stability::StabilityFilter;
+use crate::supervisor::SupervisedSender;
+use crate::worker::{Shutdown, Worker, WorkerError, WorkerResult, SHUTDOWN_CHECK_INTERVAL};
+use crate::{GroupType, SetEvent, WarningEvent};
 use derive_new::new;
-use failure::ResultExt;
-use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
-use std::path::PathBuf;
-use std::sync::mpsc::{channel, Sender};
-use std::time::Duration;
+use edit_count::protocol;
+use failure::{Error, ResultExt};
+use notify::{DebouncedEvent, RecursiveMode};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(new)]
+#[allow(clippy::too_many_arguments)]
 pub struct Listener {
+    fs: Arc<dyn FileSystem>,
     watch_frequency: u64,
+    root_retry_interval: u64,
+    fallback_poll_interval: u64,
+    coalesce_window: u64,
+    stability: StabilityFilter,
     layout: DirectoryLayout,
     listener_tx: Sender<ListenerEvent>,
+    warning_tx: SupervisedSender<WarningEvent>,
+    /// Shared with the previous Listener, so the status survives a restart
+    status: ListenerHandle,
 }
 
 impl Worker for Listener {
     type W = Listener;
     const NAME: &'static str = "Listener";
-    fn work(mut self) -> WorkerResult {
-        // Build list of all watched base paths
-        let mut watch_dirs =
-            Vec::with_capacity(self.layout.raw_dirs.len() + self.layout.render_dirs.len());
+    fn work(self, shutdown: Shutdown) -> WorkerResult {
+        // Launch the filesystem listener
+        let (watcher_tx, watcher_rx) = channel();
+        let mut watches = Watches::new(
+            self.fs.clone(),
+            watcher_tx,
+            Duration::from_millis(self.watch_frequency),
+            self.fallback_poll_interval,
+            self.status.0.clone(),
+            self.warning_tx.clone(),
+        );
+        watches.sync(&self.layout);
+        // The Crawler counts everything that exists at startup
+        watches.take_new_albums();
 
-        for path in self
-            .layout
-            .raw_dirs
-            .iter()
-            .chain(self.layout.render_dirs.iter())
-        {
-            let mut path_cache = PathBuf::new();
-            for component in path {
-                match component {
-                    PathComponent::Dir(dir) => path_cache.push(dir),
-                    _ => break,
+        // Report which watcher each layout root uses
+        for root in self.status.status()?.roots {
+            info!(path = ?root.root, mode = %root.mode, "Watching layout root");
+        }
+
+        let mut coalescer = Coalescer::new(Duration::from_millis(self.coalesce_window));
+        let retry_interval = Duration::from_millis(self.root_retry_interval);
+        let mut last_root_check = Instant::now();
+        loop {
+            if shutdown.is_triggered() {
+                // Don't lose changes that are still waiting for their window to close
+                for coalesced in coalescer.take_all() {
+                    self.recount(coalesced)?;
+                }
+                break;
+            }
+
+            let timeout = coalescer
+                .next_due(Instant::now())
+                .map_or(retry_interval, |due| due.min(retry_interval))
+                .min(SHUTDOWN_CHECK_INTERVAL);
+
+            let event = match watcher_rx.recv_timeout(timeout) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            if let Some(event) = event {
+                self.handle_event(event, &mut watches, &mut coalescer)?;
+            }
+
+            // Attach watches to roots that have been mounted since the last check,
+            // even while events from other roots keep arriving
+            let now = Instant::now();
+            if now >= last_root_check + retry_interval {
+                last_root_check = now;
+                if watches.missing_root_returned() {
+                    watches.sync(&self.layout);
+                }
+            }
+
+            // Albums found by a sync are counted like any other change
+            for (tipe, album) in watches.take_new_albums() {
+                coalescer.touch(tipe, &album, now);
+            }
+
+            // Albums that were deleted or renamed away are counted as empty, which removes them
+            for (tipe, album) in watches.take_gone_albums() {
+                coalescer.forget(tipe, &album);
+                self.send_removed(tipe, album)?;
+            }
+
+            for coalesced in coalescer.take_due(now) {
+                let (tipe, album) = (coalesced.tipe, coalesced.album.clone());
+
+                // Look at partially written files again once they have had time to settle
+                if self.recount(coalesced)? {
+                    coalescer.defer(tipe, &album, now + self.stability.min_age()?);
                 }
             }
-            watch_dirs.push(path_cache);
         }
 
-        // Launch the filesystem listener
-        let (watcher_tx, watcher_rx) = channel();
-        let mut watcher = watcher(watcher_tx, Duration::from_millis(self.watch_frequency))
-            .expect("Unable to launch Listner Worker");
+        Ok(())
+    }
+}
+
+impl Listener {
+    /// Send the stable contents of a coalesced album.
+    /// Returns whether some files were left out because they may still be written to.
+    fn recount(&self, coalesced: CoalescedAlbum) -> Result<bool, Error> {
+        let CoalescedAlbum {
+            album,
+            tipe,
+            events,
+        } = coalesced;
+        let contents = match self.stability.read_album(&*self.fs, &album)? {
+            Some(contents) => contents,
+            None => return Ok(false),
+        };
+
+        debug!(
+            group = %album.group_name,
+            album = %album.album_name,
+            files = contents.files.len(),
+            events,
+            unstable = contents.unstable,
+            "Recounting album"
+        );
+
+        let event = SetEvent {
+            group_name: album.group_name,
+            album_name: album.album_name,
+            path: album.path,
+            tipe,
+            files: contents.files,
+            events,
+        };
+
+        self.listener_tx
+            .send(ListenerEvent::Recount(event))
+            .context("Listener send channel closed.")?;
 
-        for dir in watch_dirs {
-            if let Err(e) = watcher.watch(&dir, RecursiveMode::Recursive) {
-                println!("Unable to watch directory {:?}:\n{:#?}", &dir, e);
+        Ok(contents.unstable)
+    }
+
+    /// Send an empty count for an album that no longer exists.
+    fn send_removed(&self, tipe: GroupType, album: ResolvedAlbum) -> Result<(), Error> {
+        debug!(
+            group = %album.group_name,
+            album = %album.album_name,
+            "Album directory is gone"
+        );
+
+        let event = SetEvent {
+            group_name: album.group_name,
+            album_name: album.album_name,
+            path: album.path,
+            tipe,
+            files: Vec::new(),
+            events: 1,
+        };
+
+        self.listener_tx
+            .send(ListenerEvent::Recount(event))
+            .context("Listener send channel closed.")?;
+
+        Ok(())
+    }
+
+    fn handle_event(
+        &self,
+        event: DebouncedEvent,
+        watches: &mut Watches,
+        coalescer: &mut Coalescer,
+    ) -> WorkerResult {
+        let tx = &self.listener_tx;
+
+        trace!(event = ?event, "Filesystem event");
+        self.status.record_event()?;
+
+        // Groups or albums appearing or disappearing change the set of watched directories
+        let layout_changed = match &event {
+            DebouncedEvent::Create(path) => watches.is_new_level(path),
+            DebouncedEvent::Remove(path) => watches.is_watched(path),
+            DebouncedEvent::Rename(old_path, new_path) => {
+                watches.is_watched(old_path) || watches.is_new_level(new_path)
             }
+            // The poll watcher reports a watched directory that disappeared as an error
+            DebouncedEvent::Error(_, Some(path)) => watches.is_watched(path),
+            DebouncedEvent::Rescan | DebouncedEvent::Error(_, None) => true,
+            _ => false,
+        };
+
+        if layout_changed {
+            watches.sync(&self.layout);
         }
 
-        let tx = &mut self.listener_tx;
-        for event in watcher_rx.iter() {
-            println!("Event: {:#?}", event);
+        // Changes to files inside albums are folded into a recount of the album
+        let now = Instant::now();
+        let mut coalesce = |path: &Path| -> bool {
+            let albums = watches.albums_containing(path);
+            for (tipe, album) in albums.iter() {
+                coalescer.touch(*tipe, album, now);
+            }
+            !albums.is_empty()
+        };
 
-            match event {
-                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
-                    tx.send(ListenerEvent::Exist(path))
-                        .context("Listener send channel closed.")?;
-                }
+        match event {
+            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) if !coalesce(&path) => {
+                tx.send(ListenerEvent::Exist(path))
+                    .context("Listener send channel closed.")?;
+            }
 
-                DebouncedEvent::Rename(old_path, new_path) => {
+            DebouncedEvent::Rename(old_path, new_path) => {
+                if !coalesce(&old_path) {
                     tx.send(ListenerEvent::Remove(old_path))
                         .context("Listener send channel closed.")?;
+                }
+                if !coalesce(&new_path) {
                     tx.send(ListenerEvent::Exist(new_path))
                         .context("Listener send channel closed.")?;
                 }
+            }
 
-                DebouncedEvent::Remove(path) => {
-                    tx.send(ListenerEvent::Remove(path))
-                        .context("Listener send channel closed.")?;
-                }
+            DebouncedEvent::Remove(path) if !coalesce(&path) => {
+                tx.send(ListenerEvent::Remove(path))
+                    .context("Listener send channel closed.")?;
+            }
 
-                DebouncedEvent::Rescan => {
-                    tx.send(ListenerEvent::Rescan)
-                        .context("Listener send channel closed.")?;
+            // Events were lost, so every album is recounted
+            DebouncedEvent::Rescan => {
+                for (tipe, album) in watches.all_albums() {
+                    coalescer.touch(tipe, &album, now);
                 }
+            }
 
-                DebouncedEvent::Error(error, p) => {
-                    if let Some(path) = p {
-                        println!(
-                            "Underlying filesystem watcher error at path: {:?}\n{:#?}",
-                            path, error
-                        );
-                    } else {
-                        println!("Underlying filesystem watcher error: {:#?}", error);
-                    }
-                    tx.send(ListenerEvent::Rescan)
-                        .expect("Listener send channel closed.");
-                }
+            // Removed by the sync above, like any other directory that disappears
+            DebouncedEvent::Error(error, Some(path)) if layout_changed => {
+                debug!(path = ?path, error = %error, "Watched directory is gone");
+            }
 
-                _ => (),
+            DebouncedEvent::Error(error, Some(path)) => {
+                warn!(path = ?path, error = %error, "Filesystem watcher error");
             }
+
+            DebouncedEvent::Error(error, None) => {
+                error!(error = %error, "Filesystem watcher error, recounting every album");
+                for (tipe, album) in watches.all_albums() {
+                    coalescer.touch(tipe, &album, now);
+                }
+            }
+
+            _ => (),
         }
 
         Ok(())
@@ -101,5 +276,665 @@ impl Worker for Listener {
 pub enum ListenerEvent {
     Exist(PathBuf),
     Remove(PathBuf),
-    Rescan,
+    /// The contents of an album after a burst of changes
+    Recount(SetEvent),
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ListenerStatus {
+    pub roots: Vec<RootStatus>,
+    /// Filesystem events handled since startup
+    pub events_processed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RootStatus {
+    pub root: PathBuf,
+    pub mode: WatcherMode,
+    /// Directories under the root ran out of native watches, and are polled instead.
+    pub degraded: bool,
+    /// The root doesn't exist or is empty, for example an unmounted drive.
+    pub missing: bool,
+}
+
+impl From<RootStatus> for protocol::RootStatus {
+    fn from(root: RootStatus) -> protocol::RootStatus {
+        protocol::RootStatus {
+            root: root.root.to_string_lossy().into_owned(),
+            mode: root.mode.to_string(),
+            degraded: root.degraded,
+            missing: root.missing,
+        }
+    }
+}
+
+impl From<ListenerStatus> for protocol::ListenerStatus {
+    fn from(status: ListenerStatus) -> protocol::ListenerStatus {
+        protocol::ListenerStatus {
+            roots: status.roots.into_iter().map(Into::into).collect(),
+            events_processed: status.events_processed,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct ListenerHandle(Arc<Mutex<ListenerStatus>>);
+
+impl ListenerHandle {
+    pub fn status(&self) -> Result<ListenerStatus, Error> {
+        Ok(self
+            .0
+            .lock()
+            .or(Err(WorkerError::new_resource_poisoned(
+                "Listener.status".to_string(),
+            )))?
+            .clone())
+    }
+
+    fn record_event(&self) -> Result<(), Error> {
+        self.0
+            .lock()
+            .or(Err(WorkerError::new_resource_poisoned(
+                "Listener.status".to_string(),
+            )))?
+            .events_processed += 1;
+
+        Ok(())
+    }
+}
+
+/// Whether a watch failed because the OS limit on native watches was reached.
+/// On Linux this is `ENOSPC` from `inotify_add_watch`, see `fs.inotify.max_user_watches`.
+fn is_watch_limit(error: &notify::Error) -> bool {
+    const ENOSPC: i32 = 28;
+
+    match error {
+        notify::Error::Io(e) => cfg!(target_os = "linux") && e.raw_os_error() == Some(ENOSPC),
+        _ => false,
+    }
+}
+
+/// A native watch failed because the OS limit on native watches was reached.
+#[derive(Debug, PartialEq, Eq)]
+struct WatchLimitReached;
+
+/// A watcher backend. Polling watchers are shared by every path with the same interval.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Backend {
+    Native,
+    Poll(u64),
+}
+
+impl Backend {
+    fn for_mode(mode: WatcherMode) -> Vec<Backend> {
+        match mode {
+            WatcherMode::Native => vec![Backend::Native],
+            WatcherMode::Poll { interval } => vec![Backend::Poll(interval)],
+            WatcherMode::Hybrid { interval } => vec![Backend::Native, Backend::Poll(interval)],
+        }
+    }
+}
+
+/// The directories currently registered with the filesystem watchers.
+///
+/// Only album directories and the group levels above them are watched,
+/// each non-recursively, so unrelated folders under a layout root are ignored.
+struct Watches {
+    fs: Arc<dyn FileSystem>,
+    watcher_tx: Sender<DebouncedEvent>,
+    delay: Duration,
+    /// Polling interval, in milliseconds, for directories that ran out of native watches
+    fallback_interval: u64,
+    status: Arc<Mutex<ListenerStatus>>,
+    warning_tx: SupervisedSender<WarningEvent>,
+    backends: HashMap<Backend, Box<dyn FsWatcher>>,
+    watched: HashMap<PathBuf, WatcherMode>,
+    levels: HashSet<PathBuf>,
+    /// Directories that couldn't get a native watch, and are polled instead
+    fallback: HashSet<PathBuf>,
+    /// Layout roots the UI has been warned about running out of native watches
+    warned: HashSet<PathBuf>,
+    /// Layout roots that were missing or empty at the last sync
+    missing: HashSet<PathBuf>,
+    /// Album directories, and the albums they hold
+    albums: HashMap<PathBuf, Vec<(GroupType, ResolvedAlbum)>>,
+    /// Albums found by a sync that weren't found by the one before it
+    new_albums: Vec<(GroupType, ResolvedAlbum)>,
+    /// Albums found by a sync that are gone from the one after it, while their root exists
+    gone_albums: Vec<(GroupType, ResolvedAlbum)>,
+}
+
+impl Watches {
+    fn new(
+        fs: Arc<dyn FileSystem>,
+        watcher_tx: Sender<DebouncedEvent>,
+        delay: Duration,
+        fallback_interval: u64,
+        status: Arc<Mutex<ListenerStatus>>,
+        warning_tx: SupervisedSender<WarningEvent>,
+    ) -> Watches {
+        Watches {
+            fs,
+            watcher_tx,
+            delay,
+            fallback_interval,
+            status,
+            warning_tx,
+            backends: HashMap::new(),
+            watched: HashMap::new(),
+            levels: HashSet::new(),
+            fallback: HashSet::new(),
+            warned: HashSet::new(),
+            missing: HashSet::new(),
+            albums: HashMap::new(),
+            new_albums: Vec::new(),
+            gone_albums: Vec::new(),
+        }
+    }
+
+    /// Resolve the layout and update the watchers to match it.
+    fn sync(&mut self, layout: &DirectoryLayout) {
+        let mut levels = HashSet::new();
+        let mut wanted = HashMap::new();
+        let mut missing = HashSet::new();
+        let mut albums: HashMap<PathBuf, Vec<(GroupType, ResolvedAlbum)>> = HashMap::new();
+        let paths = layout
+            .raw_dirs
+            .iter()
+            .map(|p| (GroupType::Raw, p))
+            .chain(layout.render_dirs.iter().map(|p| (GroupType::Render, p)));
+        for (tipe, path) in paths {
+            let root = path.root();
+            if !self.fs.is_mounted(&root) {
+                if !self.missing.contains(&root) {
+                    warn!(path = ?root, "Layout root is missing or empty, waiting for it");
+                }
+                missing.insert(root);
+                continue;
+            }
+
+            let mode = path.watcher;
+            let resolved = resolve(&*self.fs, &path.path);
+            for album in resolved.albums.iter() {
+                albums
+                    .entry(album.path.clone())
+                    .or_default()
+                    .push((tipe, album.clone()));
+            }
+
+            for dir in resolved
+                .albums
+                .into_iter()
+                .map(|a| a.path)
+                .chain(resolved.levels.iter().cloned())
+            {
+                // The first layout path to claim a directory decides its watcher
+                wanted.entry(dir).or_insert((mode, root.clone()));
+            }
+            levels.extend(resolved.levels);
+        }
+
+        // Directories that are gone get a native watch again if they return
+        self.fallback.retain(|dir| wanted.contains_key(dir));
+
+        let stale: Vec<(PathBuf, WatcherMode)> = self
+            .watched
+            .iter()
+            .filter(|(dir, mode)| {
+                wanted.get(*dir).map(|w| self.effective_mode(dir, w.0)) != Some(**mode)
+            })
+            .map(|(dir, mode)| (dir.clone(), *mode))
+            .collect();
+        for (dir, mode) in stale {
+            self.watched.remove(&dir);
+            for backend in Backend::for_mode(mode) {
+                if let Some(watcher) = self.backends.get_mut(&backend) {
+                    // Watches on deleted directories are already gone
+                    match watcher.unwatch(&dir) {
+                        Ok(()) | Err(notify::Error::WatchNotFound) => (),
+                        Err(e) => warn!(path = ?dir, error = %e, "Unable to unwatch directory"),
+                    }
+                }
+            }
+        }
+
+        for (dir, (configured, root)) in wanted {
+            if self.watched.contains_key(&dir) {
+                continue;
+            }
+
+            let mut mode = self.effective_mode(&dir, configured);
+            let mut watching = self.watch(&dir, mode);
+            if watching == Err(WatchLimitReached) {
+                // Only this directory is polled, the rest of the root keeps its native watches
+                self.fallback.insert(dir.clone());
+                self.degrade(&root);
+                mode = self.effective_mode(&dir, configured);
+                watching = self.watch(&dir, mode);
+            }
+
+            if watching == Ok(true) {
+                self.watched.insert(dir, mode);
+            }
+        }
+
+        for (dir, dir_albums) in albums.iter() {
+            if !self.albums.contains_key(dir) {
+                self.new_albums.extend(dir_albums.iter().cloned());
+            }
+        }
+        // Albums on a root that went missing are offline, not removed
+        for (dir, dir_albums) in self.albums.iter() {
+            if !albums.contains_key(dir) && !missing.iter().any(|root| dir.starts_with(root)) {
+                self.gone_albums.extend(dir_albums.iter().cloned());
+            }
+        }
+
+        self.levels = levels;
+        self.missing = missing;
+        self.albums = albums;
+
+        self.update_status(layout);
+    }
+
+    /// Watch `dir` with each backend `mode` uses.
+    /// Returns whether any backend is watching it, or `WatchLimitReached`
+    /// before trying the others if a native watch ran into the OS limit.
+    fn watch(&mut self, dir: &Path, mode: WatcherMode) -> Result<bool, WatchLimitReached> {
+        let mut watching = false;
+        for backend in Backend::for_mode(mode) {
+            let watcher = match self.backend(backend) {
+                Ok(w) => w,
+                Err(e) => {
+                    error!(path = ?dir, error = %e, "Unable to start watcher");
+                    continue;
+                }
+            };
+
+            match watcher.watch(dir, RecursiveMode::NonRecursive) {
+                Ok(()) => watching = true,
+                Err(ref e) if backend == Backend::Native && is_watch_limit(e) => {
+                    return Err(WatchLimitReached);
+                }
+                Err(e) => warn!(path = ?dir, error = %e, "Unable to watch directory"),
+            }
+        }
+
+        Ok(watching)
+    }
+
+    /// The watcher mode to use for a directory, after any fallback to polling.
+    fn effective_mode(&self, dir: &Path, mode: WatcherMode) -> WatcherMode {
+        if !self.fallback.contains(dir) {
+            return mode;
+        }
+
+        match mode {
+            WatcherMode::Native => WatcherMode::Poll {
+                interval: self.fallback_interval,
+            },
+            WatcherMode::Hybrid { interval } => WatcherMode::Poll { interval },
+            mode => mode,
+        }
+    }
+
+    /// Warn the UI that directories under `root` are being polled, once per root.
+    fn degrade(&mut self, root: &Path) {
+        if !self.warned.insert(root.to_path_buf()) {
+            return;
+        }
+
+        let message = format!(
+            "Ran out of native filesystem watches while watching {:?}. \
+             The directories that couldn't be watched are polled instead, \
+             so changes to them will take longer to appear. \
+             Raise the limit with `sysctl fs.inotify.max_user_watches=524288` \
+             and restart Edit Count.",
+            root
+        );
+        warn!(path = ?root, "{}", message);
+
+        let warning = WarningEvent::new(Listener::NAME.to_string(), message);
+        if self.warning_tx.send(warning).is_err() {
+            warn!("Unable to send warning, the UI Server has shut down.");
+        }
+    }
+
+    fn update_status(&self, layout: &DirectoryLayout) {
+        let roots = layout
+            .raw_dirs
+            .iter()
+            .chain(layout.render_dirs.iter())
+            .map(|path| {
+                let root = path.root();
+                RootStatus {
+                    mode: path.watcher,
+                    degraded: self.fallback.iter().any(|dir| dir.starts_with(&root)),
+                    missing: self.missing.contains(&root),
+                    root,
+                }
+            })
+            .collect();
+
+        match self.status.lock() {
+            Ok(mut status) => status.roots = roots,
+            Err(_) => error!("Listener.status was poisoned, status will not be updated."),
+        }
+    }
+
+    /// Get a watcher backend, launching it if it isn't running yet.
+    fn backend(&mut self, backend: Backend) -> Result<&mut Box<dyn FsWatcher>, Error> {
+        if !self.backends.contains_key(&backend) {
+            let tx = self.watcher_tx.clone();
+            let watcher = match backend {
+                Backend::Native => self.fs.watcher(tx, self.delay),
+                Backend::Poll(interval) => {
+                    self.fs.poll_watcher(tx, Duration::from_millis(interval))
+                }
+            }
+            .context("Unable to launch filesystem watcher")?;
+            self.backends.insert(backend, watcher);
+        }
+
+        Ok(self
+            .backends
+            .get_mut(&backend)
+            .expect("Watcher backend was just inserted"))
+    }
+
+    /// The albums `path` is directly inside of.
+    fn albums_containing(&self, path: &Path) -> Vec<(GroupType, ResolvedAlbum)> {
+        path.parent()
+            .and_then(|p| self.albums.get(p))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Every album found by the last sync.
+    fn all_albums(&self) -> Vec<(GroupType, ResolvedAlbum)> {
+        self.albums.values().flatten().cloned().collect()
+    }
+
+    /// Remove and return the albums found since the last call.
+    fn take_new_albums(&mut self) -> Vec<(GroupType, ResolvedAlbum)> {
+        self.new_albums.drain(..).collect()
+    }
+
+    /// Remove and return the albums that have disappeared since the last call.
+    fn take_gone_albums(&mut self) -> Vec<(GroupType, ResolvedAlbum)> {
+        self.gone_albums.drain(..).collect()
+    }
+
+    /// Whether any layout root that was missing at the last sync is now mounted.
+    fn missing_root_returned(&self) -> bool {
+        self.missing.iter().any(|root| self.fs.is_mounted(root))
+    }
+
+    /// Whether `path` is a new directory that may be a group or album.
+    fn is_new_level(&self, path: &Path) -> bool {
+        path.parent().is_some_and(|p| self.levels.contains(p)) && self.fs.is_dir(path)
+    }
+
+    /// Whether `path` is, or contains, a watched directory.
+    fn is_watched(&self, path: &Path) -> bool {
+        self.watched.keys().any(|dir| dir.starts_with(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory_layout::{parse_path, LayoutPath};
+    use crate::filesystem::memory::MemoryFileSystem;
+    use crate::settings::StabilitySettings;
+    use crate::worker::WorkerHandle;
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const ROOT_RETRY_INTERVAL: u64 = 50;
+
+    struct Scenario {
+        fs: MemoryFileSystem,
+        status: ListenerHandle,
+        listener: WorkerHandle,
+        rx: Receiver<ListenerEvent>,
+    }
+
+    impl Scenario {
+        /// Start a Listener watching `/photos/[G]/[A]` on `fs`, once the Listener is watching.
+        fn start(fs: MemoryFileSystem, watcher: WatcherMode) -> Scenario {
+            let layout = photos_layout(watcher);
+            let stability = StabilityFilter::new(StabilitySettings {
+                min_age: 0,
+                ..StabilitySettings::default()
+            });
+            let status = ListenerHandle::default();
+            let (listener_tx, rx) = channel();
+            let listener = Listener::new(
+                Arc::new(fs.clone()),
+                10,
+                ROOT_RETRY_INTERVAL,
+                10,
+                20,
+                stability,
+                layout,
+                listener_tx,
+                SupervisedSender::new(),
+                status.clone(),
+            )
+            .start()
+            .unwrap();
+
+            let scenario = Scenario {
+                fs,
+                status,
+                listener,
+                rx,
+            };
+            scenario.wait_until(|status| !status.roots.is_empty());
+            scenario
+        }
+
+        /// The next recount matching `expected`, skipping any others.
+        fn recount(&self, expected: impl Fn(&SetEvent) -> bool) -> SetEvent {
+            loop {
+                match self.rx.recv_timeout(TIMEOUT) {
+                    Ok(ListenerEvent::Recount(event)) if expected(&event) => return event,
+                    Ok(_) => (),
+                    Err(e) => panic!("No matching recount: {}", e),
+                }
+            }
+        }
+
+        /// Every recount sent in the next few root retry intervals.
+        fn recounts(&self) -> Vec<SetEvent> {
+            let until = Instant::now() + Duration::from_millis(ROOT_RETRY_INTERVAL * 4);
+            let mut recounts = Vec::new();
+            while let Some(timeout) = until.checked_duration_since(Instant::now()) {
+                if let Ok(ListenerEvent::Recount(event)) = self.rx.recv_timeout(timeout) {
+                    recounts.push(event);
+                }
+            }
+            recounts
+        }
+
+        fn wait_until(&self, ready: impl Fn(&ListenerStatus) -> bool) {
+            let deadline = Instant::now() + TIMEOUT;
+            while !ready(&self.status.status().unwrap()) {
+                assert!(Instant::now() < deadline, "Listener status never changed");
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+
+        fn stop(self) {
+            self.listener.stop().unwrap();
+        }
+    }
+
+    fn photos_layout(watcher: WatcherMode) -> DirectoryLayout {
+        DirectoryLayout {
+            raw_dirs: vec![LayoutPath {
+                path: parse_path("/photos/[G]/[A]").unwrap(),
+                watcher,
+            }],
+            render_dirs: Vec::new(),
+        }
+    }
+
+    fn photos() -> MemoryFileSystem {
+        let fs = MemoryFileSystem::new();
+        fs.create_dir_all("/photos/2024").unwrap();
+        fs
+    }
+
+    fn album(name: &'static str, files: usize) -> impl Fn(&SetEvent) -> bool {
+        move |event| event.album_name == name && event.files.len() == files
+    }
+
+    #[test]
+    fn new_album_is_counted() {
+        let scenario = Scenario::start(photos(), WatcherMode::Native);
+
+        scenario
+            .fs
+            .write_file("/photos/2024/Wedding/a.jpg", 10)
+            .unwrap();
+        scenario
+            .fs
+            .write_file("/photos/2024/Wedding/b.jpg", 10)
+            .unwrap();
+
+        let event = scenario.recount(album("Wedding", 2));
+        assert_eq!(event.group_name, "2024");
+        assert_eq!(event.path, PathBuf::from("/photos/2024/Wedding"));
+        assert_eq!(event.tipe, GroupType::Raw);
+        scenario.stop();
+    }
+
+    #[test]
+    fn renamed_album_is_removed_and_counted_again() {
+        let fs = photos();
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        let scenario = Scenario::start(fs, WatcherMode::Native);
+
+        scenario
+            .fs
+            .rename("/photos/2024/Wedding", "/photos/2024/Reception")
+            .unwrap();
+
+        scenario.recount(album("Wedding", 0));
+        let event = scenario.recount(album("Reception", 1));
+        assert_eq!(event.path, PathBuf::from("/photos/2024/Reception"));
+        scenario.stop();
+    }
+
+    #[test]
+    fn removed_album_is_counted_as_empty() {
+        let fs = photos();
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        let scenario = Scenario::start(fs, WatcherMode::Native);
+
+        scenario.fs.remove("/photos/2024/Wedding").unwrap();
+
+        scenario.recount(album("Wedding", 0));
+        assert!(scenario.recounts().is_empty());
+        scenario.stop();
+    }
+
+    #[test]
+    fn unmounted_root_keeps_its_albums() {
+        let fs = photos();
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        let scenario = Scenario::start(fs, WatcherMode::Poll { interval: 10 });
+
+        scenario.fs.remove("/photos").unwrap();
+        scenario.wait_until(|status| status.roots[0].missing);
+        assert!(scenario.recounts().is_empty());
+
+        // Counted again once the root is back
+        scenario
+            .fs
+            .write_file("/photos/2024/Wedding/a.jpg", 10)
+            .unwrap();
+        scenario.recount(album("Wedding", 1));
+        scenario.wait_until(|status| !status.roots[0].missing);
+        scenario.stop();
+    }
+
+    #[test]
+    fn emptied_root_keeps_its_albums() {
+        let fs = photos();
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        let scenario = Scenario::start(fs, WatcherMode::Native);
+
+        // The mount point is left behind
+        scenario.fs.remove("/photos/2024").unwrap();
+        scenario.wait_until(|status| status.roots[0].missing);
+        assert!(scenario.recounts().is_empty());
+
+        scenario
+            .fs
+            .write_file("/photos/2024/Wedding/a.jpg", 10)
+            .unwrap();
+        scenario.recount(album("Wedding", 1));
+        scenario.wait_until(|status| !status.roots[0].missing);
+        scenario.stop();
+    }
+
+    #[test]
+    fn rescan_recounts_every_album() {
+        let fs = photos();
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        fs.write_file("/photos/2023/Party/a.jpg", 10).unwrap();
+        let scenario = Scenario::start(fs, WatcherMode::Native);
+
+        scenario.fs.rescan().unwrap();
+
+        let mut recounted: Vec<String> = scenario
+            .recounts()
+            .into_iter()
+            .map(|event| event.album_name)
+            .collect();
+        recounted.sort();
+        assert_eq!(recounted, vec!["Party", "Wedding"]);
+        scenario.stop();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn only_directories_past_the_watch_limit_are_polled() {
+        let fs = photos();
+        fs.create_dir_all("/photos/2024/Wedding").unwrap();
+        fs.create_dir_all("/photos/2024/Party").unwrap();
+        fs.set_native_watch_limit(2).unwrap();
+        let (watcher_tx, _watcher_rx) = channel();
+        let mut watches = Watches::new(
+            Arc::new(fs.clone()),
+            watcher_tx,
+            Duration::from_millis(10),
+            30,
+            Arc::default(),
+            SupervisedSender::new(),
+        );
+
+        let layout = photos_layout(WatcherMode::Native);
+        watches.sync(&layout);
+
+        let modes: Vec<WatcherMode> = watches.watched.values().cloned().collect();
+        let native = modes.iter().filter(|m| **m == WatcherMode::Native).count();
+        let polled = modes
+            .iter()
+            .filter(|m| **m == WatcherMode::Poll { interval: 30 })
+            .count();
+        assert_eq!(native, 2);
+        assert_eq!(polled, modes.len() - 2);
+        assert!(polled > 0);
+        assert!(watches.status.lock().unwrap().roots[0].degraded);
+
+        // Polled directories keep polling, without being watched again
+        watches.sync(&layout);
+        assert_eq!(watches.watched.len(), modes.len());
+        assert_eq!(watches.fallback.len(), polled);
+    }
 }
@@ -0,0 +1,54 @@
+use crate::settings::LogSettings;
+use failure::{Error, ResultExt};
+use std::env;
+use std::fs;
+use std::path::Path;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer};
+
+/// Environment variable that overrides the configured log filter
+const LOG_ENV: &str = "EDIT_COUNT_LOG";
+
+/// Log to the terminal, and to rotating files in `log_dir` if enabled.
+///
+/// Records from dependencies using the `log` crate, such as gotham, are included.
+/// The returned guard flushes the log file when dropped, and must be kept until exit.
+pub fn init(settings: &LogSettings, log_dir: &Path) -> Result<Option<WorkerGuard>, Error> {
+    let filter = env::var(LOG_ENV).unwrap_or_else(|_| settings.filter.clone());
+    let terminal = fmt::layer().with_filter(parse_filter(&filter)?);
+
+    let (file, guard) = if settings.file {
+        fs::create_dir_all(log_dir).context("Unable to create log directory")?;
+        let appender = RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("edit_count")
+            .filename_suffix("log")
+            .max_log_files(settings.max_files)
+            .build(log_dir)
+            .context("Unable to open log file")?;
+        let (writer, guard) = tracing_appender::non_blocking(appender);
+        let layer = fmt::layer()
+            .json()
+            .with_writer(writer)
+            .with_filter(parse_filter(&filter)?);
+
+        (Some(layer), Some(guard))
+    } else {
+        (None, None)
+    };
+
+    tracing_subscriber::registry()
+        .with(terminal)
+        .with(file)
+        .try_init()
+        .context("Unable to start logging")?;
+
+    Ok(guard)
+}
+
+fn parse_filter(filter: &str) -> Result<EnvFilter, Error> {
+    Ok(EnvFilter::try_new(filter).with_context(|_| format!("Invalid log filter '{}'", filter))?)
+}
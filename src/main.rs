@@ -1,31 +1,59 @@
+// failure_derive generates its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
+mod args;
+mod coalescer;
 mod counter;
 mod crawler;
 mod directory_layout;
+mod filesystem;
 mod listener;
+mod logging;
+mod metrics;
+mod resolver;
 mod settings;
+mod stability;
+mod supervisor;
 mod ui_server;
 mod worker;
 
-use crate::settings::Settings;
+use crate::args::{Args, Command};
+use crate::settings::watcher::SettingsWatcher;
+use crate::settings::{init, Settings, SettingsHandle};
+use crate::supervisor::{RestartPolicy, SupervisedSender, Supervisor};
+use crate::ui_server::api::ApiHandles;
+use crate::ui_server::dispatcher::{Dispatcher, DispatcherHandle};
+use crate::ui_server::websockets_server::WebsocketsServer;
 use crate::ui_server::UIServer;
-use crate::worker::Worker;
+use crate::worker::{Shutdown, Worker, SHUTDOWN_CHECK_INTERVAL};
 use app_dirs::{app_root, AppDataType, AppInfo};
-use failure::Error;
-use std::sync::mpsc::channel;
+use derive_new::new;
+use edit_count::protocol;
+use failure::{format_err, Error};
+use std::collections::HashSet;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::sync::Arc;
 
-use crate::counter::Counter;
-use crate::crawler::Crawler;
-use crate::listener::{Listener, ListenerEvent};
-use json::{object, JsonValue};
+use crate::counter::{Counter, CounterHandle};
+use crate::crawler::{Crawler, CrawlerHandle};
+use crate::filesystem::{FileSystem, RealFileSystem};
+use crate::listener::{Listener, ListenerEvent, ListenerHandle};
+use crate::stability::StabilityFilter;
 use std::ffi::OsString;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
 
 #[derive(Debug)]
 pub enum DirectoryUpdateEvent {
     Exist(PathBuf),
     Remove(PathBuf),
     Set(SetEvent),
+    Offline(OfflineEvent),
+    /// Drop every album the layout no longer matches, after it changes
+    Retain(RetainEvent),
     Refresh,
 }
 
@@ -33,8 +61,29 @@ pub enum DirectoryUpdateEvent {
 pub struct SetEvent {
     group_name: String,
     album_name: String,
+    /// The album directory that was read
+    path: PathBuf,
     tipe: GroupType,
     files: Vec<OsString>,
+    /// The number of filesystem events folded into this update, 0 for a crawl
+    events: usize,
+}
+
+/// The albums the directory layout matches, after it changes.
+#[derive(Debug)]
+pub struct RetainEvent {
+    /// (type, group name, album name) of every album found
+    albums: HashSet<(GroupType, String, String)>,
+    /// Layout roots that don't exist, so their albums can't be found and are kept
+    missing_roots: Vec<(GroupType, PathBuf)>,
+}
+
+/// A layout root is no longer mounted, so the albums on it are offline.
+#[derive(Debug)]
+pub struct OfflineEvent {
+    tipe: GroupType,
+    root: PathBuf,
+    since: SystemTime,
 }
 
 #[derive(Debug, Clone)]
@@ -42,19 +91,63 @@ pub struct CountUpdateEvent {
     group_name: String,
     album_name: String,
     count: Count,
+    /// Raw files with a render of the same name
+    edited: usize,
+    /// Set while the album is on an offline root, and the counts are the last known values
+    offline_since: Option<SystemTime>,
+    /// The number of filesystem events folded into this update
+    events: usize,
+    /// The sequence number of the album's latest change
+    sequence: u64,
+    /// The album no longer exists, and the counts are all zero
+    removed: bool,
 }
 
 unsafe impl Send for CountUpdateEvent {}
 unsafe impl Sync for CountUpdateEvent {}
 
-impl Into<JsonValue> for CountUpdateEvent {
-    fn into(self) -> JsonValue {
-        object! {
-            "group_name" => self.group_name,
-            "album_name" => self.album_name,
-            "total" => self.count.total,
-            "raw" => self.count.raw,
-            "render" => self.count.render
+impl From<CountUpdateEvent> for protocol::AlbumCount {
+    fn from(event: CountUpdateEvent) -> protocol::AlbumCount {
+        protocol::AlbumCount {
+            group_name: event.group_name,
+            album_name: event.album_name,
+            total: event.count.total,
+            raw: event.count.raw,
+            render: event.count.render,
+            edited: event.edited,
+            offline: event.offline_since.is_some(),
+            offline_since: event
+                .offline_since
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            events: event.events,
+            sequence: event.sequence,
+        }
+    }
+}
+
+impl From<CountUpdateEvent> for protocol::RemovedAlbum {
+    fn from(event: CountUpdateEvent) -> protocol::RemovedAlbum {
+        protocol::RemovedAlbum {
+            group_name: event.group_name,
+            album_name: event.album_name,
+            sequence: event.sequence,
+        }
+    }
+}
+
+/// A problem that the UI should show to the user.
+#[derive(Debug, Clone, new)]
+pub struct WarningEvent {
+    source: String,
+    message: String,
+}
+
+impl From<WarningEvent> for protocol::Message {
+    fn from(event: WarningEvent) -> protocol::Message {
+        protocol::Message::Warning {
+            source: event.source,
+            message: event.message,
         }
     }
 }
@@ -69,7 +162,7 @@ pub struct Count {
 unsafe impl Send for Count {}
 unsafe impl Sync for Count {}
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum GroupType {
     Raw,
     Render,
@@ -81,61 +174,324 @@ fn main() -> Result<(), Error> {
         author: "edit_count",
     };
 
+    let args = Args::parse(env::args().skip(1))?;
+
     // Load settings
-    let settings_root = app_root(AppDataType::UserConfig, &APP_INFO)?;
-    let settings = Settings::from(settings_root.join("settings.toml"))?;
-    let settings = Arc::new(settings);
+    // Made absolute, so it can be matched against filesystem events
+    let settings_path = match &args.config {
+        Some(path) => env::current_dir()?.join(path),
+        None => app_root(AppDataType::UserConfig, &APP_INFO)?.join("settings.toml"),
+    };
+    match args.command {
+        Some(Command::Init) => return init::run(&settings_path),
+        Some(Command::Help) => {
+            println!("{}", args::USAGE);
+            return Ok(());
+        }
+        None => (),
+    }
+
+    // First run
+    let created_default = if settings_path.exists() {
+        false
+    } else if io::stdin().is_terminal() {
+        init::run(&settings_path)?;
+        false
+    } else {
+        init::write_default(&settings_path)?;
+        true
+    };
+
+    let settings = SettingsHandle::new(Settings::from(settings_path.clone(), &args.overrides)?);
+    let initial = settings.get()?;
+
+    // Held until exit, so the log file is flushed
+    let log_root = app_root(AppDataType::UserData, &APP_INFO)?;
+    let _log_guard = logging::init(&initial.logging, &log_root.join("logs"))?;
+
+    if created_default {
+        warn!(path = ?settings_path, "Created default settings. Add directory paths to start counting, or run edit_count init");
+    }
+
+    let static_dir = match &args.static_dir {
+        Some(path) => Some(env::current_dir()?.join(path)),
+        None => None,
+    };
+
+    let fs: Arc<dyn FileSystem> = Arc::new(RealFileSystem);
+
+    let validation = initial.validate(&*fs);
+    validation.log();
+    if !validation.is_valid() {
+        return Err(format_err!(
+            "Invalid settings in {}",
+            settings_path.display()
+        ));
+    }
+
+    let stability = StabilityFilter::new(initial.stability.clone());
 
     // Create channels
+    // Channels received by supervised workers are rebuilt when those workers restart
     let (listener_tx, listener_rx) = channel();
-    let (cue_tx, cue_rx) = channel();
-    let (due_tx, due_rx) = channel();
+    let (settings_tx, settings_rx) = channel();
+    let cue_tx = SupervisedSender::new();
+    let due_tx = SupervisedSender::new();
+    let warning_tx = SupervisedSender::new();
+
+    // State that outlives worker restarts
+    let listener_status = ListenerHandle::default();
+    let crawler_status = CrawlerHandle::default();
+    let counter_status = CounterHandle::default();
+    let dispatcher_status = DispatcherHandle::default();
 
     // Start workers
-    let listener_handle = Listener::new(
-        settings.watch_frequency,
-        settings.directory_layout.clone(),
-        listener_tx,
-    )
-    .start()?;
-
-    let crawler_handle = Crawler::new(settings.directory_layout.clone(), due_tx.clone()).start()?;
-
-    let counter = Counter::new(cue_tx, due_rx);
-
-    let ui_server_handle = UIServer::new(
-        settings.web_port,
-        settings.ws_port,
-        cue_rx,
-        counter.get_handle(),
-    )
-    .start()?;
-
-    let counter_handle = counter.start()?;
-
-    println!("Server started at http://127.0.0.1:{}", settings.web_port);
-
-    // Dispatch Listener events
-    while let Ok(event) = listener_rx.recv() {
-        match event {
-            ListenerEvent::Exist(path) => {
-                due_tx.send(DirectoryUpdateEvent::Exist(path))?;
-            }
-            ListenerEvent::Remove(path) => {
-                due_tx.send(DirectoryUpdateEvent::Remove(path))?;
-            }
-            ListenerEvent::Rescan => {
-                // TODO: Crawl directory structure
-                unimplemented!();
+    let mut supervisor = Supervisor::default();
+    let supervisor_status = supervisor.get_handle();
+    // Workers using the filesystem or ports back off, as the cause of a failure may take time to clear.
+    // Workers that only hold state in memory are restarted straight away, so counts keep flowing.
+    // The legacy websockets server is left stopped, until its settings change.
+    let backoff = RestartPolicy::Backoff {
+        initial: Duration::from_secs(1),
+        max: Duration::from_secs(60),
+    };
+    let always = RestartPolicy::Always;
+    let never = RestartPolicy::Never;
+
+    {
+        let (fs, settings, stability) = (fs.clone(), settings.clone(), stability.clone());
+        let (warning_tx, listener_status) = (warning_tx.clone(), listener_status.clone());
+        supervisor.supervise(backoff, move || {
+            let settings = settings.get()?;
+            Ok(Listener::new(
+                fs.clone(),
+                settings.watch_frequency,
+                settings.root_retry_interval,
+                settings.fallback_poll_interval,
+                settings.coalesce_window,
+                stability.clone(),
+                settings.directory_layout.clone(),
+                listener_tx.clone(),
+                warning_tx.clone(),
+                listener_status.clone(),
+            ))
+        })?;
+    }
+
+    {
+        let (fs, settings, stability) = (fs.clone(), settings.clone(), stability.clone());
+        let (due_tx, crawler_status) = (due_tx.clone(), crawler_status.clone());
+        supervisor.supervise(backoff, move || {
+            let settings = settings.get()?;
+            Ok(Crawler::new(
+                fs.clone(),
+                settings.root_retry_interval,
+                stability.clone(),
+                settings.directory_layout.clone(),
+                due_tx.clone(),
+                crawler_status.clone(),
+            ))
+        })?;
+    }
+
+    {
+        let (cue_tx, due_tx, settings) = (cue_tx.clone(), due_tx.clone(), settings.clone());
+        let counter_status = counter_status.clone();
+        supervisor.supervise(always, move || {
+            Ok(Counter::new(
+                cue_tx.clone(),
+                due_tx.take_receiver()?,
+                settings.get()?.removed_album_retention,
+                counter_status.clone(),
+            ))
+        })?;
+    }
+
+    {
+        let (warning_tx, dispatcher_status) = (warning_tx.clone(), dispatcher_status.clone());
+        supervisor.supervise(always, move || {
+            Ok(Dispatcher::new(
+                cue_tx.take_receiver()?,
+                warning_tx.take_receiver()?,
+                dispatcher_status.clone(),
+            ))
+        })?;
+    }
+
+    let api_handles = ApiHandles::new(
+        supervisor_status,
+        listener_status,
+        crawler_status,
+        counter_status,
+        dispatcher_status,
+    );
+    {
+        let (settings, api_handles) = (settings.clone(), api_handles.clone());
+        supervisor.supervise(backoff, move || {
+            Ok(UIServer::new(
+                settings.get()?.web_port,
+                static_dir.clone(),
+                api_handles.clone(),
+            ))
+        })?;
+    }
+
+    // Only started when ws_port is set
+    {
+        let settings = settings.clone();
+        let build = move || {
+            let port = settings
+                .get()?
+                .ws_port
+                .ok_or_else(|| format_err!("ws_port is not set"))?;
+            Ok(WebsocketsServer::new(port, api_handles.clone()))
+        };
+        if initial.ws_port.is_some() {
+            supervisor.supervise(never, build)?;
+        } else {
+            supervisor.register(never, build)?;
+        }
+    }
+
+    {
+        let fs = fs.clone();
+        supervisor.supervise(backoff, move || {
+            Ok(SettingsWatcher::new(
+                fs.clone(),
+                settings_path.clone(),
+                args.overrides.clone(),
+                settings_tx.clone(),
+                warning_tx.clone(),
+            ))
+        })?;
+    }
+
+    // Stop on Ctrl-C, or SIGTERM from a service manager
+    let shutdown = Shutdown::default();
+    let handler_shutdown = shutdown.clone();
+    ctrlc::set_handler(move || handler_shutdown.trigger())?;
+
+    info!("Server started at http://127.0.0.1:{}", initial.web_port);
+
+    // Dispatch Listener events, apply new settings, and restart any workers that have failed
+    while !shutdown.is_triggered() {
+        match listener_rx.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+            Ok(event) => dispatch(event, &due_tx)?,
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        for new in settings_rx.try_iter() {
+            reload(new, &settings, &stability, &*fs, &mut supervisor, &due_tx)?;
+        }
+        supervisor.check()?;
+    }
+
+    info!("Shutting down");
+
+    // Stop workers in the order data flows through them, so nothing in flight is lost
+    supervisor.stop(Listener::NAME)?;
+    for event in listener_rx.try_iter() {
+        dispatch(event, &due_tx)?;
+    }
+    supervisor.stop_all()?;
+
+    Ok(())
+}
+
+/// Forward an event from the Listener to the Counter.
+fn dispatch(
+    event: ListenerEvent,
+    due_tx: &SupervisedSender<DirectoryUpdateEvent>,
+) -> Result<(), Error> {
+    match event {
+        ListenerEvent::Exist(path) => {
+            due_tx.send(DirectoryUpdateEvent::Exist(path))?;
+        }
+        ListenerEvent::Remove(path) => {
+            due_tx.send(DirectoryUpdateEvent::Remove(path))?;
+        }
+        ListenerEvent::Recount(event) => {
+            due_tx.send(DirectoryUpdateEvent::Set(event))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply changed settings, restarting only the workers that use them.
+fn reload(
+    new: Settings,
+    settings: &SettingsHandle,
+    stability: &StabilityFilter,
+    fs: &dyn FileSystem,
+    supervisor: &mut Supervisor,
+    due_tx: &SupervisedSender<DirectoryUpdateEvent>,
+) -> Result<(), Error> {
+    let old = settings.get()?;
+    let layout_changed = old.directory_layout != new.directory_layout;
+
+    if old.stability != new.stability {
+        stability.set_settings(new.stability.clone())?;
+    }
+    if old.logging != new.logging {
+        warn!("Logging settings take effect after a restart");
+    }
+
+    // Workers read the settings as they start, so they must be updated first
+    settings.set(new.clone())?;
+
+    if layout_changed
+        || old.watch_frequency != new.watch_frequency
+        || old.root_retry_interval != new.root_retry_interval
+        || old.fallback_poll_interval != new.fallback_poll_interval
+        || old.coalesce_window != new.coalesce_window
+    {
+        supervisor.replace(Listener::NAME)?;
+    }
+    if layout_changed || old.root_retry_interval != new.root_retry_interval {
+        supervisor.replace(Crawler::NAME)?;
+    }
+    if old.removed_album_retention != new.removed_album_retention {
+        supervisor.replace(Counter::NAME)?;
+    }
+    if layout_changed {
+        // Forget albums that the new layout no longer matches
+        let mut albums = HashSet::new();
+        let mut missing_roots = Vec::new();
+        for (tipe, paths) in [
+            (GroupType::Raw, &new.directory_layout.raw_dirs),
+            (GroupType::Render, &new.directory_layout.render_dirs),
+        ]
+        .iter()
+        {
+            for layout_path in paths.iter() {
+                let root = layout_path.root();
+                if !fs.is_mounted(&root) {
+                    missing_roots.push((*tipe, root));
+                    continue;
+                }
+                for album in resolver::resolve(fs, &layout_path.path).albums {
+                    albums.insert((*tipe, album.group_name, album.album_name));
+                }
             }
         }
+        let event = RetainEvent {
+            albums,
+            missing_roots,
+        };
+        due_tx.send(DirectoryUpdateEvent::Retain(event))?;
+    }
+    if old.web_port != new.web_port {
+        supervisor.replace(UIServer::NAME)?;
+    }
+    if old.ws_port != new.ws_port {
+        match new.ws_port {
+            Some(_) => supervisor.replace(WebsocketsServer::NAME)?,
+            None => supervisor.stop(WebsocketsServer::NAME)?,
+        }
     }
 
-    // Wait for workers
-    ui_server_handle.join()?;
-    listener_handle.join()?;
-    crawler_handle.join()?;
-    counter_handle.join()?;
+    info!("Settings applied");
 
     Ok(())
 }
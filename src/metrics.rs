@@ -0,0 +1,127 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// A Prometheus histogram of durations.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// Observations in each bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&le| seconds <= le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+/// Writes metrics in the Prometheus text exposition format.
+#[derive(Debug, Default)]
+pub struct MetricsWriter(String);
+
+impl MetricsWriter {
+    pub fn new() -> MetricsWriter {
+        MetricsWriter::default()
+    }
+
+    /// Start a metric family. Must be called before writing its samples.
+    pub fn family(&mut self, name: &str, tipe: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, tipe);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+
+    pub fn histogram(&mut self, name: &str, histogram: &Histogram) {
+        let mut cumulative = 0;
+        for (le, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            let le = le.to_string();
+            self.sample(
+                &format!("{}_bucket", name),
+                &[("le", &le)],
+                cumulative as f64,
+            );
+        }
+        self.sample(
+            &format!("{}_bucket", name),
+            &[("le", "+Inf")],
+            histogram.count as f64,
+        );
+        self.sample(&format!("{}_sum", name), &[], histogram.sum);
+        self.sample(&format!("{}_count", name), &[], histogram.count as f64);
+    }
+
+    pub fn finish(self) -> String {
+        self.0
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_values_are_escaped() {
+        let mut w = MetricsWriter::new();
+        w.family("edit_count_album_files", "gauge", "Files in an album");
+        w.sample(
+            "edit_count_album_files",
+            &[("group", "C:\\Photos"), ("album", "The \"Big\" Day\n2")],
+            3.0,
+        );
+
+        assert_eq!(
+            w.finish(),
+            "# HELP edit_count_album_files Files in an album\n\
+             # TYPE edit_count_album_files gauge\n\
+             edit_count_album_files{group=\"C:\\\\Photos\",album=\"The \\\"Big\\\" Day\\n2\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(40));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut w = MetricsWriter::new();
+        w.histogram("edit_count_crawl_duration_seconds", &histogram);
+        let text = w.finish();
+
+        assert!(text.contains("edit_count_crawl_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("edit_count_crawl_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(text.contains("edit_count_crawl_duration_seconds_bucket{le=\"30\"} 2\n"));
+        assert!(text.contains("edit_count_crawl_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("edit_count_crawl_duration_seconds_count 3\n"));
+    }
+}
@@ -0,0 +1,292 @@
+//! The websocket protocol spoken over the `ec-ws` subprotocol.
+//!
+//! Every message is a JSON object, tagged with its `type`.
+//! A client starts by sending `hello` with the version it speaks,
+//! and the server replies with its own version, or an `unsupported_version` error.
+//!
+//! ```json
+//! {"type": "hello", "id": 1, "version": 1}
+//! {"type": "full_count", "id": 2}
+//! {"type": "album_files", "id": 3, "group": "2019", "album": "Wedding"}
+//! ```
+//!
+//! `full_count` and `changes` carry a `nonce` and `sequence`. A client that reconnects
+//! sends them back in `resume`, and only receives the albums changed while it was away.
+//!
+//! Replies carry the `id` of their request, when it had one.
+//! Messages without an `id`, such as `count_update`, are broadcast by the server.
+//!
+//! The bodies of the HTTP API's JSON responses are at the end of this module.
+//!
+//! This module only depends on serde, so clients can use it through the `edit_count` library.
+
+use serde_derive::{Deserialize, Serialize};
+
+/// The version of the protocol the server speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// A message from a client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    /// Returned in the reply, to match it to this request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    /// Must be sent before any other command
+    Hello { version: u32 },
+    /// The counts for every album
+    FullCount,
+    /// The counts for the albums changed since `since`, after reconnecting.
+    /// Answered with a `full_count` if the changes are no longer known.
+    Resume { nonce: u64, since: u64 },
+    /// The watched layout roots
+    ListenerStatus,
+    /// The state of every worker
+    WorkerStatus,
+    /// What still needs editing in one album
+    AlbumFiles { group: String, album: String },
+    /// Only receive the `count_update`s matching this, or any other subscription.
+    /// Clients without a subscription receive every update.
+    Subscribe(Subscription),
+    /// Remove a subscription, or every subscription if none is given
+    Unsubscribe {
+        #[serde(default)]
+        subscription: Option<u64>,
+    },
+}
+
+/// A message from the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    /// The `id` of the request this replies to, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub message: Message,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    Hello {
+        version: u32,
+    },
+    /// Every album, current to `sequence`
+    FullCount {
+        nonce: u64,
+        sequence: u64,
+        albums: Vec<AlbumCount>,
+    },
+    /// The albums changed since a `resume`, current to `sequence`
+    Changes {
+        nonce: u64,
+        sequence: u64,
+        albums: Vec<AlbumCount>,
+        /// Albums removed since the `resume`
+        removed: Vec<RemovedAlbum>,
+    },
+    ListenerStatus {
+        roots: Vec<RootStatus>,
+        events_processed: u64,
+    },
+    WorkerStatus {
+        workers: Vec<WorkerStatus>,
+    },
+    AlbumFiles(AlbumFiles),
+    Subscribed {
+        subscription: u64,
+    },
+    Unsubscribed {
+        subscriptions: Vec<u64>,
+    },
+    /// Broadcast when an album is counted
+    CountUpdate(AlbumCount),
+    /// Broadcast when an album is deleted, renamed, or has no files left
+    AlbumRemoved(RemovedAlbum),
+    /// Broadcast when there's a problem the user should know about
+    Warning {
+        source: String,
+        message: String,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request wasn't valid JSON, or wasn't a known command
+    InvalidRequest,
+    /// A command was sent before `hello`
+    HandshakeRequired,
+    /// The server doesn't speak the version the client asked for
+    UnsupportedVersion,
+    /// The group, album or subscription doesn't exist
+    NotFound,
+    /// The server failed to answer
+    Internal,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlbumCount {
+    pub group_name: String,
+    pub album_name: String,
+    /// Raw files counted since the server started, including those deleted since
+    pub total: usize,
+    pub raw: usize,
+    pub render: usize,
+    /// Raw files with a render of the same name
+    pub edited: usize,
+    pub offline: bool,
+    /// When the album's root went offline, in unix seconds
+    pub offline_since: Option<u64>,
+    /// The number of filesystem events folded into this update, 0 for a crawl
+    pub events: usize,
+    /// The sequence number of the album's latest change.
+    /// Updates with a sequence number at or below the client's are already known, and can be ignored.
+    pub sequence: u64,
+}
+
+impl AlbumCount {
+    /// Whether every remaining raw file has been edited.
+    pub fn is_complete(&self) -> bool {
+        self.edited >= self.raw
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemovedAlbum {
+    pub group_name: String,
+    pub album_name: String,
+    /// The sequence number of the removal
+    pub sequence: u64,
+}
+
+/// Which `count_update`s a client wants. Every field that is set must match.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Subscription {
+    /// Only albums in these groups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+    /// Only albums whose name matches this glob, where `*` matches anything and `?` one character
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    /// Only albums that aren't complete, and the update that completes them
+    #[serde(default)]
+    pub incomplete: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RootStatus {
+    pub root: String,
+    pub mode: String,
+    pub degraded: bool,
+    pub missing: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub policy: String,
+    pub state: String,
+    /// Seconds since the worker started, while it's running
+    pub uptime: Option<u64>,
+    pub restarts: usize,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlbumFiles {
+    pub group_name: String,
+    pub album_name: String,
+    /// Raw files with no render of the same name
+    pub unedited: Vec<String>,
+    /// Renders with no raw file of the same name
+    pub unmatched_renders: Vec<String>,
+    /// Raw files that were counted, but have since been deleted
+    pub deleted: Vec<String>,
+}
+
+/// `GET /api/status`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub workers: Vec<WorkerStatus>,
+    pub listener: ListenerStatus,
+    pub crawler: CrawlerStatus,
+    pub websockets: WebsocketsStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListenerStatus {
+    pub roots: Vec<RootStatus>,
+    pub events_processed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrawlerStatus {
+    /// When the last crawl of a layout path finished, in unix seconds
+    pub last_crawl: Option<u64>,
+    pub last_crawl_duration_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebsocketsStatus {
+    /// Connected web UI clients
+    pub subscribers: usize,
+}
+
+/// An entry of `GET /api/groups`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupSummary {
+    pub group_name: String,
+    pub albums: usize,
+    pub incomplete_albums: usize,
+    /// Raw files counted since the server started, including those deleted since
+    pub total: usize,
+    pub raw: usize,
+    pub render: usize,
+    /// Raw files with a render of the same name
+    pub edited: usize,
+    pub deleted: usize,
+    /// The fraction of `total` that has been edited or deleted, from 0 to 1
+    pub completion: f64,
+    pub complete: bool,
+    /// When a file last changed, in unix seconds
+    pub last_activity: Option<u64>,
+}
+
+/// `GET /api/groups/:group/albums/:album`, and an entry of `GET /api/groups/:group/albums`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlbumSummary {
+    pub group_name: String,
+    pub album_name: String,
+    /// Raw files counted since the server started, including those deleted since
+    pub total: usize,
+    pub raw: usize,
+    pub render: usize,
+    /// Raw files with a render of the same name
+    pub edited: usize,
+    pub deleted: usize,
+    /// The fraction of `total` that has been edited or deleted, from 0 to 1
+    pub completion: f64,
+    pub complete: bool,
+    pub offline: bool,
+    /// When the album's root went offline, in unix seconds
+    pub offline_since: Option<u64>,
+    /// When a file last changed, in unix seconds
+    pub last_activity: Option<u64>,
+}
+
+/// The body of a failed HTTP API request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiError {
+    pub error: String,
+}
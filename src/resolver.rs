@@ -0,0 +1,205 @@
+use super::directory_layout::{AlbumType, DirectoryPath, PathComponent};
+use crate::filesystem::FileSystem;
+use std::path::PathBuf;
+use tracing::warn;
+
+/// The albums a `DirectoryPath` currently matches on disk.
+#[derive(Debug, Default)]
+pub struct ResolvedPath {
+    pub albums: Vec<ResolvedAlbum>,
+    /// Directories that were listed to find the groups and albums.
+    /// A directory appearing or disappearing inside one of these can change the result.
+    pub levels: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolvedAlbum {
+    pub group_name: String,
+    pub album_name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Debug)]
+struct NamedPath {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+pub fn resolve(fs: &dyn FileSystem, path: &DirectoryPath) -> ResolvedPath {
+    let mut levels = Vec::new();
+    let mut path_cache = PathBuf::new();
+    let mut groups: Vec<NamedPath> = Vec::new();
+    let mut filled_groups: Vec<(NamedPath, Vec<NamedPath>)> = Vec::new();
+    for component in path {
+        match component {
+            PathComponent::Group(group) => {
+                // Process group
+
+                let mut new_groups: Vec<NamedPath> = Vec::new();
+                if groups.is_empty() {
+                    for entry in
+                        deep_list(fs, &path_cache, group.depth - 1, group.depth, &mut levels)
+                    {
+                        new_groups.push(NamedPath {
+                            name: entry.to_string_lossy().into_owned(),
+                            path: path_cache.join(entry),
+                        });
+                    }
+                } else {
+                    for old_group in groups {
+                        let search_path = old_group.path.join(&path_cache);
+                        for entry in
+                            deep_list(fs, &search_path, group.depth - 1, group.depth, &mut levels)
+                        {
+                            new_groups.push(NamedPath {
+                                name: format!(
+                                    "{}:{}",
+                                    old_group.name,
+                                    entry.to_string_lossy().into_owned(),
+                                ),
+                                path: search_path.join(entry),
+                            });
+                        }
+                    }
+                }
+
+                groups = new_groups;
+                path_cache = PathBuf::new();
+            }
+
+            PathComponent::Album(album) => {
+                // Process album
+
+                // If the groups Vec is empty, then no groups were found.
+                // Put all albums into a virtual group that will be hidden in the web view.
+                if groups.is_empty() {
+                    groups = vec![NamedPath {
+                        name: String::from("%default%"),
+                        path: PathBuf::new(),
+                    }];
+                }
+
+                for group in groups {
+                    // Determine search depth
+                    let (filter_depth, search_depth) = match &album.tipe {
+                        AlbumType::Single => (0, 1),
+                        AlbumType::Depth => (album.min - 1, album.min),
+                        AlbumType::Range => (album.min - 1, album.max),
+                    };
+
+                    let group_path = group.path.join(&path_cache);
+                    let entries =
+                        deep_list(fs, &group_path, filter_depth, search_depth, &mut levels);
+                    let mut album_cache = Vec::with_capacity(entries.len());
+
+                    for entry in entries {
+                        album_cache.push(NamedPath {
+                            name: entry.to_string_lossy().into_owned(),
+                            path: group_path.join(entry),
+                        });
+                    }
+
+                    filled_groups.push((group, album_cache));
+                }
+
+                groups = Vec::new();
+                path_cache = PathBuf::new();
+            }
+
+            PathComponent::Dir(dir) => {
+                // Process path
+
+                path_cache.push(dir);
+            }
+        }
+    }
+
+    if !path_cache.as_os_str().is_empty() {
+        for group in &mut filled_groups {
+            for album in &mut group.1 {
+                // The album's subdirectory may not exist yet, so the album itself must be watched
+                levels.push(album.path.clone());
+                album.path.push(&path_cache);
+            }
+        }
+    }
+
+    let mut albums = Vec::new();
+    for (group, group_albums) in filled_groups {
+        for NamedPath { name, path } in group_albums {
+            albums.push(ResolvedAlbum {
+                group_name: group.name.clone(),
+                album_name: name,
+                path,
+            });
+        }
+    }
+
+    levels.sort();
+    levels.dedup();
+
+    ResolvedPath { albums, levels }
+}
+
+/// List directories between `filter_depth` and `search_depth` levels below `base`.
+/// Every directory successfully listed is added to `levels`.
+fn deep_list(
+    fs: &dyn FileSystem,
+    base: &PathBuf,
+    filter_depth: usize,
+    search_depth: usize,
+    levels: &mut Vec<PathBuf>,
+) -> Vec<PathBuf> {
+    let mut final_dirs = Vec::new();
+    let mut search_paths: Vec<PathBuf> = vec![base.into()];
+
+    for i in 0..search_depth {
+        let mut new_search_paths: Vec<PathBuf> = Vec::new();
+        for path in search_paths {
+            // The loop is bootstrapped with the base path.
+            // All other entries in new_dirs will be relative and must be joined to the base path.
+            let full_path = if i == 0 {
+                path.clone()
+            } else {
+                base.join(&path)
+            };
+
+            // Warn and skip if listing failed
+            let dirs = match fs.read_dir(&full_path) {
+                Ok(d) => d,
+                Err(e) => {
+                    warn!(path = ?full_path, error = %e, "Unable to list directory");
+                    continue;
+                }
+            };
+            levels.push(full_path);
+
+            for entry in dirs {
+                // Ignore files
+                if entry.is_dir {
+                    // Don't include base path
+                    let name: PathBuf = if i == 0 {
+                        entry.name.into()
+                    } else {
+                        path.join(entry.name)
+                    };
+                    new_search_paths.push(name);
+                }
+            }
+        }
+
+        if new_search_paths.is_empty() {
+            break;
+        }
+
+        if i >= filter_depth {
+            for path in new_search_paths.iter() {
+                final_dirs.push(path.clone());
+            }
+        }
+
+        search_paths = new_search_paths;
+    }
+
+    final_dirs
+}
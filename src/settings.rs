@@ -1,22 +1,228 @@
+pub mod init;
+pub mod validation;
+pub mod watcher;
+
 use crate::directory_layout::DirectoryLayout;
-use config::{Config, ConfigError, File};
+use crate::worker::WorkerError;
+use config::{Config, ConfigError, Environment, File};
+use failure::Error;
+use regex::Regex;
+use serde::de::{self, Deserialize, Deserializer};
 use serde_derive::Deserialize;
+use std::fmt;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub watch_frequency: u64,
+    /// How often to check for missing layout roots, in milliseconds
+    #[serde(default = "default_root_retry_interval")]
+    pub root_retry_interval: u64,
+    /// How often to scan directories that couldn't get a native watch, in milliseconds
+    #[serde(default = "default_fallback_poll_interval")]
+    pub fallback_poll_interval: u64,
+    /// How long to collect filesystem events for an album before recounting it, in milliseconds
+    #[serde(default = "default_coalesce_window")]
+    pub coalesce_window: u64,
+    /// How long to keep the raw files counted in a removed album, in milliseconds
+    #[serde(default = "default_removed_album_retention")]
+    pub removed_album_retention: u64,
     pub web_port: u16,
-    pub ws_port: u16,
+    /// Also serve websockets on their own port, for web UIs that don't connect to `/ws`
+    #[serde(default)]
+    pub ws_port: Option<u16>,
     pub directory_layout: DirectoryLayout,
+    #[serde(default)]
+    pub stability: StabilitySettings,
+    #[serde(default)]
+    pub logging: LogSettings,
+}
+
+fn default_root_retry_interval() -> u64 {
+    5000
+}
+
+fn default_fallback_poll_interval() -> u64 {
+    10_000
+}
+
+fn default_coalesce_window() -> u64 {
+    2000
+}
+
+fn default_removed_album_retention() -> u64 {
+    86_400_000
+}
+
+/// When a file is considered completely written, and can be counted.
+#[derive(Debug, Deserialize, Clone)]
+pub struct StabilitySettings {
+    /// How long since a file was last modified before it is counted, in milliseconds
+    #[serde(default = "default_min_age")]
+    pub min_age: u64,
+    /// File names matching any of these patterns are never counted
+    #[serde(
+        default = "default_temp_patterns",
+        deserialize_with = "deserialize_patterns"
+    )]
+    pub temp_patterns: Vec<Regex>,
+}
+
+impl PartialEq for StabilitySettings {
+    fn eq(&self, other: &Self) -> bool {
+        self.min_age == other.min_age
+            && self.temp_patterns.len() == other.temp_patterns.len()
+            && self
+                .temp_patterns
+                .iter()
+                .zip(other.temp_patterns.iter())
+                .all(|(a, b)| a.as_str() == b.as_str())
+    }
+}
+
+impl Default for StabilitySettings {
+    fn default() -> Self {
+        StabilitySettings {
+            min_age: default_min_age(),
+            temp_patterns: default_temp_patterns(),
+        }
+    }
+}
+
+fn default_min_age() -> u64 {
+    2000
+}
+
+fn default_temp_patterns() -> Vec<Regex> {
+    [r"^\.", r"\.tmp$", r"\.part$"]
+        .iter()
+        .map(|p| Regex::new(p).expect("Default temp pattern is invalid"))
+        .collect()
+}
+
+fn deserialize_patterns<'de, D>(deserializer: D) -> Result<Vec<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let unparsed: Vec<String> = Deserialize::deserialize(deserializer)?;
+    let mut parsed = Vec::with_capacity(unparsed.len());
+    for p in unparsed {
+        parsed.push(Regex::new(&p).map_err(de::Error::custom)?);
+    }
+
+    Ok(parsed)
+}
+
+/// What is logged, and where.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct LogSettings {
+    /// Comma separated filter directives, such as `info,edit_count::listener=debug`.
+    /// Overridden by the `EDIT_COUNT_LOG` environment variable.
+    #[serde(default = "default_log_filter")]
+    pub filter: String,
+    /// Also write JSON logs to daily rotating files in the app data directory
+    #[serde(default)]
+    pub file: bool,
+    /// How many rotated log files to keep
+    #[serde(default = "default_max_log_files")]
+    pub max_files: usize,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            filter: default_log_filter(),
+            file: false,
+            max_files: default_max_log_files(),
+        }
+    }
+}
+
+fn default_log_filter() -> String {
+    "info".to_string()
+}
+
+fn default_max_log_files() -> usize {
+    7
+}
+
+/// How a layout path is watched for changes.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum WatcherMode {
+    /// The platform's native filesystem events, such as inotify.
+    #[default]
+    Native,
+    /// Scan the directories every `interval` milliseconds.
+    /// Needed for network shares, where native events miss changes made by other machines.
+    Poll { interval: u64 },
+    /// Native events, with a polling scan every `interval` milliseconds to catch anything missed.
+    Hybrid { interval: u64 },
+}
+
+impl fmt::Display for WatcherMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatcherMode::Native => write!(f, "native"),
+            WatcherMode::Poll { interval } => write!(f, "poll ({}ms)", interval),
+            WatcherMode::Hybrid { interval } => write!(f, "hybrid ({}ms)", interval),
+        }
+    }
+}
+
+/// Settings given on the command line, which take precedence over the file and environment.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub web_port: Option<u16>,
+    pub ws_port: Option<u16>,
 }
 
 impl Settings {
-    pub fn from(config_file: PathBuf) -> Result<Self, ConfigError> {
+    /// Load the settings from `config_file`, overridden by `EDIT_COUNT_` environment variables,
+    /// then by `overrides`.
+    /// Nested settings use `__` in environment variables, such as `EDIT_COUNT_STABILITY__MIN_AGE`.
+    pub fn from(config_file: PathBuf, overrides: &Overrides) -> Result<Self, ConfigError> {
         let mut s = Config::new();
 
         s.merge(File::from(config_file))?;
+        s.merge(Environment::with_prefix("EDIT_COUNT").separator("__"))?;
+
+        if let Some(port) = overrides.web_port {
+            s.set("web_port", i64::from(port))?;
+        }
+        if let Some(port) = overrides.ws_port {
+            s.set("ws_port", i64::from(port))?;
+        }
 
         s.try_into()
     }
 }
+
+/// The current Settings, which are replaced when settings.toml changes.
+#[derive(Debug, Clone)]
+pub struct SettingsHandle(Arc<Mutex<Arc<Settings>>>);
+
+impl SettingsHandle {
+    pub fn new(settings: Settings) -> SettingsHandle {
+        SettingsHandle(Arc::new(Mutex::new(Arc::new(settings))))
+    }
+
+    pub fn get(&self) -> Result<Arc<Settings>, Error> {
+        Ok(self
+            .0
+            .lock()
+            .or(Err(WorkerError::new_resource_poisoned(
+                "Settings".to_string(),
+            )))?
+            .clone())
+    }
+
+    pub fn set(&self, settings: Settings) -> Result<(), Error> {
+        *self.0.lock().or(Err(WorkerError::new_resource_poisoned(
+            "Settings".to_string(),
+        )))? = Arc::new(settings);
+
+        Ok(())
+    }
+}
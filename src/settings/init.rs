@@ -0,0 +1,166 @@
+use super::WatcherMode;
+use crate::directory_layout::{parse_path, LayoutPath};
+use failure::{format_err, Error, ResultExt};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// A commented settings.toml, with placeholders for the values asked for by `run`
+const TEMPLATE: &str = include_str!("default_settings.toml");
+
+const DEFAULT_WEB_PORT: u16 = 52220;
+
+/// Write settings.toml with the default settings and no layout paths.
+pub fn write_default(path: &Path) -> Result<(), Error> {
+    write(path, &render(&[], &[], DEFAULT_WEB_PORT))
+}
+
+/// Ask for the layout paths and port, and write them to settings.toml.
+pub fn run(path: &Path) -> Result<(), Error> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+
+    println!("Creating {}", path.display());
+    if path.exists() && !ask_yes_no(&mut input, "Settings already exist. Replace them?")? {
+        return Ok(());
+    }
+
+    println!();
+    println!("Directory paths use [G] to mark the group folder, and [A] the album folder.");
+    println!(
+        "For example, /home/me/Photos/[G]/[A] counts every album in every year folder of Photos."
+    );
+    println!("See README.md for the full Directory Path syntax.");
+
+    println!();
+    println!(
+        "Where are raw files kept? Enter one directory path per line, and a blank line to finish."
+    );
+    let raw_dirs = ask_paths(&mut input, true)?;
+
+    println!();
+    println!(
+        "Where are renders kept? Enter one directory path per line, and a blank line to finish."
+    );
+    let render_dirs = ask_paths(&mut input, false)?;
+
+    println!();
+    let web_port = ask_port(&mut input, "Web UI port", DEFAULT_WEB_PORT)?;
+
+    write(path, &render(&raw_dirs, &render_dirs, web_port))?;
+    println!();
+    println!("Saved {}", path.display());
+
+    Ok(())
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("Unable to create settings directory")?;
+    }
+    fs::write(path, contents).context("Unable to write settings")?;
+
+    Ok(())
+}
+
+fn render(raw_dirs: &[String], render_dirs: &[String], web_port: u16) -> String {
+    TEMPLATE
+        .replace("{web_port}", &web_port.to_string())
+        .replace("{raw_dirs}", &toml_array(raw_dirs))
+        .replace("{render_dirs}", &toml_array(render_dirs))
+}
+
+fn toml_array(values: &[String]) -> String {
+    let values: Vec<String> = values
+        .iter()
+        .map(|v| {
+            // Literal strings keep Windows paths readable, but can't hold a '
+            if v.contains('\'') {
+                format!("\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\""))
+            } else {
+                format!("'{}'", v)
+            }
+        })
+        .collect();
+
+    format!("[{}]", values.join(", "))
+}
+
+/// Read directory paths until a blank line, re-asking for any that can't be parsed.
+fn ask_paths(input: &mut impl BufRead, required: bool) -> Result<Vec<String>, Error> {
+    let mut paths = Vec::new();
+    loop {
+        let line = prompt(input, "> ")?;
+        if line.is_empty() {
+            if required && paths.is_empty() {
+                println!("At least one directory path is needed.");
+                continue;
+            }
+            return Ok(paths);
+        }
+
+        let path = match parse_path(&line) {
+            Ok(path) => path,
+            Err(e) => {
+                print!("{}", e);
+                continue;
+            }
+        };
+
+        let root = LayoutPath {
+            path,
+            watcher: WatcherMode::default(),
+        }
+        .root();
+        if !root.is_dir()
+            && !ask_yes_no(
+                input,
+                &format!("{} does not exist yet. Use it anyway?", root.display()),
+            )?
+        {
+            continue;
+        }
+
+        paths.push(line);
+    }
+}
+
+fn ask_port(input: &mut impl BufRead, name: &str, default: u16) -> Result<u16, Error> {
+    loop {
+        let line = prompt(input, &format!("{} [{}]: ", name, default))?;
+        if line.is_empty() {
+            return Ok(default);
+        }
+
+        match line.parse() {
+            Ok(port) if port > 0 => return Ok(port),
+            _ => println!("Ports must be a number from 1 to 65535."),
+        }
+    }
+}
+
+fn ask_yes_no(input: &mut impl BufRead, question: &str) -> Result<bool, Error> {
+    loop {
+        match prompt(input, &format!("{} [y/n]: ", question))?
+            .to_lowercase()
+            .as_str()
+        {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => (),
+        }
+    }
+}
+
+/// Print `message`, and read a trimmed line.
+fn prompt(input: &mut impl BufRead, message: &str) -> Result<String, Error> {
+    print!("{}", message);
+    io::stdout().flush()?;
+
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(format_err!("Input ended before the settings were complete"));
+    }
+
+    Ok(line.trim().to_string())
+}
@@ -0,0 +1,318 @@
+use super::{Settings, WatcherMode};
+use crate::directory_layout::LayoutPath;
+use crate::filesystem::FileSystem;
+use crate::resolver;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use tracing::{error, warn};
+
+/// Something wrong with one setting.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Where the setting is in settings.toml, such as `directory_layout.raw_dirs[0]`
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+/// Every problem found in the Settings.
+/// Settings with errors can't be used, settings with only warnings can.
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub errors: Vec<Problem>,
+    pub warnings: Vec<Problem>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Log every problem, with its key.
+    pub fn log(&self) {
+        for problem in self.errors.iter() {
+            error!(key = %problem.key, "{}", problem.message);
+        }
+        for problem in self.warnings.iter() {
+            warn!(key = %problem.key, "{}", problem.message);
+        }
+    }
+
+    fn error(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(Problem {
+            key: key.into(),
+            message: message.into(),
+        });
+    }
+
+    fn warn(&mut self, key: impl Into<String>, message: impl Into<String>) {
+        self.warnings.push(Problem {
+            key: key.into(),
+            message: message.into(),
+        });
+    }
+}
+
+impl fmt::Display for Validation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for problem in self.errors.iter() {
+            writeln!(f, "error: {}", problem)?;
+        }
+        for problem in self.warnings.iter() {
+            writeln!(f, "warning: {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Settings {
+    /// Check for settings that can be loaded, but wouldn't work.
+    pub fn validate(&self, fs: &dyn FileSystem) -> Validation {
+        let mut v = Validation::default();
+
+        if self.watch_frequency == 0 {
+            v.error("watch_frequency", "must be greater than 0");
+        }
+        if self.root_retry_interval == 0 {
+            v.error("root_retry_interval", "must be greater than 0");
+        }
+        if self.fallback_poll_interval == 0 {
+            v.error("fallback_poll_interval", "must be greater than 0");
+        }
+        // Unsettled albums are read again every min_age, so 0 would read them in a busy loop
+        if self.stability.min_age == 0 {
+            v.error("stability.min_age", "must be greater than 0");
+        }
+        if Some(self.web_port) == self.ws_port {
+            v.error(
+                "ws_port",
+                format!(
+                    "must be different from web_port, both are {}",
+                    self.web_port
+                ),
+            );
+        }
+        if self.logging.max_files == 0 {
+            v.error("logging.max_files", "must be greater than 0");
+        }
+
+        let layout = &self.directory_layout;
+        if layout.raw_dirs.is_empty() {
+            v.warn(
+                "directory_layout.raw_dirs",
+                "is empty, so nothing will be counted",
+            );
+        }
+
+        let raw = layout_paths("directory_layout.raw_dirs", &layout.raw_dirs);
+        let render = layout_paths("directory_layout.render_dirs", &layout.render_dirs);
+        for (key, layout_path) in raw.iter().chain(render.iter()) {
+            validate_layout_path(&mut v, key, layout_path, fs);
+        }
+
+        // A directory matched by both a raw and a render path would have every file counted twice
+        let mut raw_albums: HashMap<PathBuf, &str> = HashMap::new();
+        for (key, layout_path) in raw.iter() {
+            for album in resolve(fs, layout_path) {
+                raw_albums.entry(album).or_insert(key);
+            }
+        }
+        for (render_key, render_path) in render.iter() {
+            if let Some((raw_key, _)) = raw.iter().find(|(_, p)| p.path == render_path.path) {
+                v.error(render_key.as_str(), format!("is the same as {}", raw_key));
+                continue;
+            }
+
+            let overlap = resolve(fs, render_path)
+                .into_iter()
+                .find_map(|album| raw_albums.get(&album).map(|raw_key| (album, *raw_key)));
+            if let Some((album, raw_key)) = overlap {
+                v.error(
+                    render_key.as_str(),
+                    format!(
+                        "matches {}, which is also matched by {}",
+                        album.display(),
+                        raw_key
+                    ),
+                );
+            }
+        }
+
+        v
+    }
+}
+
+/// Each layout path, with its key.
+fn layout_paths<'a>(key: &str, paths: &'a [LayoutPath]) -> Vec<(String, &'a LayoutPath)> {
+    paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| (format!("{}[{}]", key, i), path))
+        .collect()
+}
+
+fn validate_layout_path(
+    v: &mut Validation,
+    key: &str,
+    layout_path: &LayoutPath,
+    fs: &dyn FileSystem,
+) {
+    match layout_path.watcher {
+        WatcherMode::Poll { interval } | WatcherMode::Hybrid { interval } if interval == 0 => {
+            v.error(
+                format!("{}.watcher.interval", key),
+                "must be greater than 0",
+            );
+        }
+        _ => (),
+    }
+
+    let root = layout_path.root();
+    if !fs.is_dir(&root) {
+        v.warn(
+            key,
+            format!(
+                "{} does not exist. It will be counted once it appears",
+                root.display()
+            ),
+        );
+    }
+}
+
+/// The album directories a layout path matches now, or none if its root is missing.
+fn resolve(fs: &dyn FileSystem, layout_path: &LayoutPath) -> Vec<PathBuf> {
+    if !fs.is_dir(&layout_path.root()) {
+        return Vec::new();
+    }
+
+    resolver::resolve(fs, &layout_path.path)
+        .albums
+        .into_iter()
+        .map(|album| album.path)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory_layout::{parse_path, DirectoryLayout};
+    use crate::filesystem::memory::MemoryFileSystem;
+
+    fn layout_paths(paths: &[&str]) -> Vec<LayoutPath> {
+        paths
+            .iter()
+            .map(|p| LayoutPath {
+                path: parse_path(p).unwrap(),
+                watcher: WatcherMode::Native,
+            })
+            .collect()
+    }
+
+    fn settings(raw_dirs: &[&str], render_dirs: &[&str]) -> Settings {
+        Settings {
+            watch_frequency: 1000,
+            root_retry_interval: 5000,
+            fallback_poll_interval: 10_000,
+            coalesce_window: 2000,
+            removed_album_retention: 86_400_000,
+            web_port: 52220,
+            ws_port: None,
+            directory_layout: DirectoryLayout {
+                raw_dirs: layout_paths(raw_dirs),
+                render_dirs: layout_paths(render_dirs),
+            },
+            stability: Default::default(),
+            logging: Default::default(),
+        }
+    }
+
+    fn photos() -> MemoryFileSystem {
+        let fs = MemoryFileSystem::new();
+        fs.write_file("/photos/2024/Wedding/a.jpg", 10).unwrap();
+        fs.write_file("/photos/2024/Wedding/Edits/a.jpg", 10)
+            .unwrap();
+        fs
+    }
+
+    fn keys(problems: &[Problem]) -> Vec<&str> {
+        problems.iter().map(|p| p.key.as_str()).collect()
+    }
+
+    #[test]
+    fn working_settings_have_no_problems() {
+        let v = settings(&["/photos/[G]/[A]"], &["/photos/[G]/[A]/Edits"]).validate(&photos());
+
+        assert!(v.is_valid());
+        assert!(v.warnings.is_empty());
+    }
+
+    #[test]
+    fn zeroes_and_shared_ports_are_errors() {
+        let mut s = settings(&["/photos/[G]/[A]"], &[]);
+        s.watch_frequency = 0;
+        s.root_retry_interval = 0;
+        s.fallback_poll_interval = 0;
+        s.ws_port = Some(s.web_port);
+        s.logging.max_files = 0;
+        s.directory_layout.raw_dirs[0].watcher = WatcherMode::Poll { interval: 0 };
+
+        let v = s.validate(&photos());
+
+        assert_eq!(
+            keys(&v.errors),
+            vec![
+                "watch_frequency",
+                "root_retry_interval",
+                "fallback_poll_interval",
+                "ws_port",
+                "logging.max_files",
+                "directory_layout.raw_dirs[0].watcher.interval",
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_min_age_is_an_error() {
+        let mut s = settings(&["/photos/[G]/[A]"], &[]);
+        s.stability.min_age = 0;
+
+        let v = s.validate(&photos());
+
+        assert_eq!(keys(&v.errors), vec!["stability.min_age"]);
+    }
+
+    #[test]
+    fn missing_roots_and_empty_layouts_are_warnings() {
+        let v = settings(&[], &["/drive/[G]/[A]"]).validate(&photos());
+
+        assert!(v.is_valid());
+        assert_eq!(
+            keys(&v.warnings),
+            vec![
+                "directory_layout.raw_dirs",
+                "directory_layout.render_dirs[0]"
+            ]
+        );
+    }
+
+    #[test]
+    fn render_paths_matching_raw_albums_are_errors() {
+        let fs = photos();
+
+        let v = settings(&["/photos/[G]/[A]"], &["/photos/[G]/[A]"]).validate(&fs);
+        assert_eq!(keys(&v.errors), vec!["directory_layout.render_dirs[0]"]);
+        assert!(v.errors[0].message.contains("raw_dirs[0]"));
+
+        // Different paths that find the same album
+        let v = settings(&["/photos/[G]/[A]"], &["/photos/[A2]"]).validate(&fs);
+        assert_eq!(keys(&v.errors), vec!["directory_layout.render_dirs[0]"]);
+        assert!(v.errors[0].message.contains("Wedding"));
+    }
+}
@@ -0,0 +1,116 @@
+use super::{Overrides, Settings};
+use crate::filesystem::FileSystem;
+use crate::supervisor::SupervisedSender;
+use crate::worker::{Shutdown, Worker, WorkerResult, SHUTDOWN_CHECK_INTERVAL};
+use crate::WarningEvent;
+use derive_new::new;
+use failure::{format_err, ResultExt};
+use notify::{DebouncedEvent, RecursiveMode};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// How long to wait for an editor to finish saving, in milliseconds
+const SAVE_DELAY: u64 = 500;
+
+/// Watches settings.toml, and sends the new Settings whenever it is saved.
+/// Invalid settings are reported, and not sent.
+#[derive(new)]
+pub struct SettingsWatcher {
+    fs: Arc<dyn FileSystem>,
+    path: PathBuf,
+    overrides: Overrides,
+    settings_tx: Sender<Settings>,
+    warning_tx: SupervisedSender<WarningEvent>,
+}
+
+impl Worker for SettingsWatcher {
+    type W = SettingsWatcher;
+    const NAME: &'static str = "Settings Watcher";
+
+    fn work(self, shutdown: Shutdown) -> WorkerResult {
+        // Editors often save by replacing the file, so watch the directory holding it
+        let dir = self
+            .path
+            .parent()
+            .ok_or_else(|| format_err!("Settings file {:?} has no parent", self.path))?;
+        let (watcher_tx, watcher_rx) = channel();
+        let mut watcher = self
+            .fs
+            .watcher(watcher_tx, Duration::from_millis(SAVE_DELAY))?;
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .context("Unable to watch settings directory")?;
+
+        loop {
+            let event = match watcher_rx.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) if shutdown.is_triggered() => break,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            let saved = match &event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Rename(_, path) => *path == self.path,
+                _ => false,
+            };
+            if saved {
+                self.reload()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SettingsWatcher {
+    fn reload(&self) -> WorkerResult {
+        let settings = match Settings::from(self.path.clone(), &self.overrides) {
+            Ok(settings) => settings,
+            Err(e) => {
+                error!(path = ?self.path, error = %e, "Invalid settings, keeping the current ones");
+                return self.warn(format!(
+                    "{} is invalid, the current settings will be kept.\n{}",
+                    self.path.display(),
+                    e
+                ));
+            }
+        };
+
+        let validation = settings.validate(&*self.fs);
+        validation.log();
+        if !validation.is_valid() {
+            error!(path = ?self.path, "Invalid settings, keeping the current ones");
+            return self.warn(format!(
+                "{} is invalid, the current settings will be kept.\n{}",
+                self.path.display(),
+                validation
+            ));
+        }
+        if !validation.warnings.is_empty() {
+            self.warn(format!(
+                "{} has problems.\n{}",
+                self.path.display(),
+                validation
+            ))?;
+        }
+
+        info!(path = ?self.path, "Settings changed");
+        self.settings_tx
+            .send(settings)
+            .context("Settings channel closed.")?;
+
+        Ok(())
+    }
+
+    fn warn(&self, message: String) -> WorkerResult {
+        self.warning_tx
+            .send(WarningEvent::new(Self::NAME.to_string(), message))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use crate::filesystem::{DirEntry, FileSystem};
+use crate::resolver::ResolvedAlbum;
+use crate::settings::StabilitySettings;
+use crate::worker::WorkerError;
+use failure::Error;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Keeps partially written files out of the counts.
+///
+/// A file is only counted once it doesn't match a temp file pattern, isn't empty,
+/// is at least `min_age` old, and hasn't changed size since its album was last read.
+/// Clones share the sizes they have seen, so the Crawler and Listener agree on what is stable.
+#[derive(Debug, Clone)]
+pub struct StabilityFilter(Arc<Mutex<FilterState>>);
+
+#[derive(Debug)]
+struct FilterState {
+    settings: StabilitySettings,
+    /// File sizes from the last read of each album
+    sizes: HashMap<PathBuf, HashMap<OsString, u64>>,
+}
+
+/// The files in an album that are ready to be counted.
+#[derive(Debug)]
+pub struct AlbumContents {
+    pub files: Vec<OsString>,
+    /// Some files were left out because they may still be written to.
+    /// The album should be read again later.
+    pub unstable: bool,
+}
+
+impl StabilityFilter {
+    pub fn new(settings: StabilitySettings) -> StabilityFilter {
+        StabilityFilter(Arc::new(Mutex::new(FilterState {
+            settings,
+            sizes: HashMap::new(),
+        })))
+    }
+
+    /// Replace the settings, keeping the sizes already seen.
+    pub fn set_settings(&self, settings: StabilitySettings) -> Result<(), Error> {
+        self.0
+            .lock()
+            .or(Err(WorkerError::new_resource_poisoned(
+                "StabilityFilter".to_string(),
+            )))?
+            .settings = settings;
+
+        Ok(())
+    }
+
+    /// How long a file must go unmodified before it is counted.
+    pub fn min_age(&self) -> Result<Duration, Error> {
+        let state = self.0.lock().or(Err(WorkerError::new_resource_poisoned(
+            "StabilityFilter".to_string(),
+        )))?;
+
+        Ok(Duration::from_millis(state.settings.min_age))
+    }
+
+    /// Read the stable files in `album`, or `None` if the album can't be read.
+    pub fn read_album(
+        &self,
+        fs: &dyn FileSystem,
+        album: &ResolvedAlbum,
+    ) -> Result<Option<AlbumContents>, Error> {
+        let entries = fs.read_dir(&album.path);
+        let mut state = self.0.lock().or(Err(WorkerError::new_resource_poisoned(
+            "StabilityFilter".to_string(),
+        )))?;
+
+        match entries {
+            Ok(entries) => Ok(Some(state.filter(&album.path, entries, SystemTime::now()))),
+            Err(e) => {
+                warn!(
+                    path = ?album.path,
+                    group = %album.group_name,
+                    album = %album.album_name,
+                    error = %e,
+                    "Error reading album"
+                );
+                state.sizes.remove(&album.path);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl FilterState {
+    fn filter(
+        &mut self,
+        album_path: &PathBuf,
+        entries: Vec<DirEntry>,
+        now: SystemTime,
+    ) -> AlbumContents {
+        let min_age = Duration::from_millis(self.settings.min_age);
+        let last_sizes = self.sizes.remove(album_path).unwrap_or_default();
+        let mut sizes = HashMap::with_capacity(entries.len());
+        let mut files = Vec::with_capacity(entries.len());
+        let mut unstable = false;
+
+        for entry in entries {
+            // Subdirectories are counted as they are found
+            if entry.is_dir {
+                files.push(entry.name);
+                continue;
+            }
+
+            // Temp files are renamed when complete, which produces a new event
+            let name = entry.name.to_string_lossy();
+            if self
+                .settings
+                .temp_patterns
+                .iter()
+                .any(|p| p.is_match(&name))
+            {
+                continue;
+            }
+
+            let last_size = last_sizes.get(&entry.name);
+            let settled = match entry.modified {
+                // Files old enough are settled on the first look, unless they have since grown
+                Some(modified) => {
+                    now.duration_since(modified).unwrap_or_default() >= min_age
+                        && last_size.is_none_or(|size| *size == entry.size)
+                }
+                // Without a modification time, only an unchanged size shows the file is complete
+                None => last_size == Some(&entry.size),
+            };
+
+            if settled {
+                // Empty files are placeholders, and writing to them produces a new event
+                if entry.size > 0 {
+                    files.push(entry.name.clone());
+                }
+            } else {
+                unstable = true;
+            }
+            sizes.insert(entry.name, entry.size);
+        }
+
+        self.sizes.insert(album_path.clone(), sizes);
+
+        AlbumContents { files, unstable }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::memory::MemoryFileSystem;
+
+    const MIN_AGE: u64 = 2000;
+
+    fn state() -> FilterState {
+        FilterState {
+            settings: StabilitySettings {
+                min_age: MIN_AGE,
+                ..StabilitySettings::default()
+            },
+            sizes: HashMap::new(),
+        }
+    }
+
+    fn file(name: &str, size: u64, modified: Option<SystemTime>) -> DirEntry {
+        DirEntry {
+            name: OsString::from(name),
+            is_dir: false,
+            size,
+            modified,
+        }
+    }
+
+    fn names(contents: &AlbumContents) -> Vec<&str> {
+        let mut names: Vec<&str> = contents.files.iter().map(|f| f.to_str().unwrap()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn old_files_are_counted_on_the_first_look() {
+        let now = SystemTime::now();
+        let old = Some(now - Duration::from_secs(3600));
+        let path = PathBuf::from("/photos/2024/Wedding");
+
+        let contents = state().filter(&path, vec![file("a.jpg", 10, old)], now);
+
+        assert_eq!(names(&contents), vec!["a.jpg"]);
+        assert!(!contents.unstable);
+    }
+
+    #[test]
+    fn recent_files_wait_for_min_age() {
+        let now = SystemTime::now();
+        let path = PathBuf::from("/photos/2024/Wedding");
+        let mut state = state();
+
+        let contents = state.filter(&path, vec![file("a.jpg", 10, Some(now))], now);
+        assert!(contents.files.is_empty());
+        assert!(contents.unstable);
+
+        let later = now + Duration::from_millis(MIN_AGE);
+        let contents = state.filter(&path, vec![file("a.jpg", 10, Some(now))], later);
+        assert_eq!(names(&contents), vec!["a.jpg"]);
+        assert!(!contents.unstable);
+    }
+
+    #[test]
+    fn growing_files_are_not_counted() {
+        let now = SystemTime::now();
+        let old = Some(now - Duration::from_secs(3600));
+        let path = PathBuf::from("/photos/2024/Wedding");
+        let mut state = state();
+
+        state.filter(&path, vec![file("a.jpg", 10, old)], now);
+        let contents = state.filter(&path, vec![file("a.jpg", 20, old)], now);
+        assert!(contents.files.is_empty());
+        assert!(contents.unstable);
+
+        let contents = state.filter(&path, vec![file("a.jpg", 20, old)], now);
+        assert_eq!(names(&contents), vec!["a.jpg"]);
+    }
+
+    #[test]
+    fn files_without_a_modified_time_need_two_looks() {
+        let now = SystemTime::now();
+        let path = PathBuf::from("/photos/2024/Wedding");
+        let mut state = state();
+
+        let contents = state.filter(&path, vec![file("a.jpg", 10, None)], now);
+        assert!(contents.files.is_empty());
+        assert!(contents.unstable);
+
+        let contents = state.filter(&path, vec![file("a.jpg", 10, None)], now);
+        assert_eq!(names(&contents), vec!["a.jpg"]);
+    }
+
+    #[test]
+    fn temp_and_empty_files_are_skipped_and_directories_kept() {
+        let now = SystemTime::now();
+        let old = Some(now - Duration::from_secs(3600));
+        let path = PathBuf::from("/photos/2024/Wedding");
+        let entries = vec![
+            file("a.jpg", 10, old),
+            file(".a.jpg.swp", 10, old),
+            file("b.jpg.part", 10, old),
+            file("c.tmp", 10, old),
+            file("placeholder.jpg", 0, old),
+            DirEntry {
+                name: OsString::from("Selects"),
+                is_dir: true,
+                size: 0,
+                modified: None,
+            },
+        ];
+
+        let contents = state().filter(&path, entries, now);
+
+        assert_eq!(names(&contents), vec!["Selects", "a.jpg"]);
+        assert!(!contents.unstable);
+    }
+
+    #[test]
+    fn read_album_uses_modified_times() {
+        let fs = MemoryFileSystem::new();
+        fs.write_file("/photos/2024/Wedding/old.jpg", 10).unwrap();
+        fs.write_file("/photos/2024/Wedding/new.jpg", 10).unwrap();
+        fs.set_modified(
+            "/photos/2024/Wedding/old.jpg",
+            SystemTime::now() - Duration::from_secs(3600),
+        )
+        .unwrap();
+        let album = ResolvedAlbum {
+            group_name: "2024".to_string(),
+            album_name: "Wedding".to_string(),
+            path: PathBuf::from("/photos/2024/Wedding"),
+        };
+        let filter = StabilityFilter::new(StabilitySettings {
+            min_age: MIN_AGE,
+            ..StabilitySettings::default()
+        });
+
+        let contents = filter.read_album(&fs, &album).unwrap().unwrap();
+        assert_eq!(names(&contents), vec!["old.jpg"]);
+        assert!(contents.unstable);
+
+        fs.remove("/photos/2024/Wedding").unwrap();
+        assert!(filter.read_album(&fs, &album).unwrap().is_none());
+    }
+}
@@ -0,0 +1,474 @@
+use crate::worker::{Worker, WorkerError, WorkerHandle, WorkerResult};
+use edit_count::protocol;
+use failure::Error;
+use std::fmt;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn};
+
+/// What to do when a Worker returns an error or panics.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Leave the Worker stopped, reporting it as failed
+    Never,
+    /// Restart straight away
+    Always,
+    /// Wait `initial`, doubling on each consecutive failure up to `max`.
+    /// The delay resets once the Worker has stayed up for `max`.
+    Backoff { initial: Duration, max: Duration },
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::Backoff { initial, max } => write!(
+                f,
+                "backoff ({}ms - {}ms)",
+                initial.as_millis(),
+                max.as_millis()
+            ),
+        }
+    }
+}
+
+/// Starts Workers, and restarts them according to their RestartPolicy when they fail.
+///
+/// The Supervisor doesn't have a thread of its own, `check` must be called regularly.
+#[derive(Default)]
+pub struct Supervisor {
+    workers: Vec<Supervised>,
+    status: SupervisorHandle,
+}
+
+struct Supervised {
+    name: &'static str,
+    policy: RestartPolicy,
+    /// Builds and starts a new instance of the Worker
+    start: Box<dyn FnMut() -> Result<WorkerHandle, Error>>,
+    handle: Option<WorkerHandle>,
+    started: Instant,
+    started_at: SystemTime,
+    /// Failures since the Worker last stayed up, for backoff
+    failures: u32,
+    restart_at: Option<Instant>,
+    restarts: usize,
+    last_error: Option<String>,
+}
+
+impl Supervisor {
+    pub fn get_handle(&self) -> SupervisorHandle {
+        self.status.clone()
+    }
+
+    /// Start a Worker built by `build`, which is called again for every restart.
+    pub fn supervise<W, F>(&mut self, policy: RestartPolicy, build: F) -> Result<(), Error>
+    where
+        W: Worker + 'static,
+        F: FnMut() -> Result<W, Error> + 'static,
+    {
+        self.register(policy, build)?;
+        let worker = self.workers.last_mut().expect("worker was just registered");
+        worker.handle = Some((worker.start)()?);
+
+        self.update_status()
+    }
+
+    /// Add a Worker built by `build` without starting it, such as one disabled by the settings.
+    /// It is started by `replace`.
+    pub fn register<W, F>(&mut self, policy: RestartPolicy, mut build: F) -> Result<(), Error>
+    where
+        W: Worker + 'static,
+        F: FnMut() -> Result<W, Error> + 'static,
+    {
+        self.workers.push(Supervised {
+            name: W::NAME,
+            policy,
+            start: Box::new(move || build()?.start()),
+            handle: None,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            failures: 0,
+            restart_at: None,
+            restarts: 0,
+            last_error: None,
+        });
+
+        self.update_status()
+    }
+
+    /// Restart any Workers that have failed, and are due for a restart.
+    pub fn check(&mut self) -> Result<(), Error> {
+        let now = Instant::now();
+        let mut changed = false;
+
+        for worker in self.workers.iter_mut() {
+            let finished = worker.handle.as_ref().is_some_and(|h| h.is_finished());
+            if finished {
+                changed = true;
+                let handle = worker.handle.take().expect("handle checked above");
+                if let Err(e) = handle.join() {
+                    error!(worker = worker.name, error = %e, "Worker failed");
+                    worker.last_error = Some(e.to_string());
+                    worker.schedule_restart(now);
+                }
+            }
+
+            if worker.restart_at.is_some_and(|at| at <= now) {
+                changed = true;
+                worker.restart_at = None;
+                worker.restarts += 1;
+                info!(
+                    worker = worker.name,
+                    restarts = worker.restarts,
+                    "Restarting worker"
+                );
+
+                match (worker.start)() {
+                    Ok(handle) => {
+                        worker.handle = Some(handle);
+                        worker.started = now;
+                        worker.started_at = SystemTime::now();
+                    }
+                    Err(e) => {
+                        error!(worker = worker.name, error = %e, "Unable to restart worker");
+                        worker.last_error = Some(e.to_string());
+                        worker.schedule_restart(now);
+                    }
+                }
+            }
+        }
+
+        if changed {
+            self.update_status()?;
+        }
+
+        Ok(())
+    }
+
+    /// Stop the Worker called `name`, without restarting it.
+    pub fn stop(&mut self, name: &str) -> WorkerResult {
+        let result = match self.workers.iter_mut().find(|w| w.name == name) {
+            Some(worker) => worker.stop(),
+            None => Ok(()),
+        };
+        self.update_status()?;
+
+        result
+    }
+
+    /// Replace the Worker called `name` with a newly built one, such as after the settings change.
+    /// This isn't counted as a restart.
+    pub fn replace(&mut self, name: &str) -> WorkerResult {
+        if let Some(worker) = self.workers.iter_mut().find(|w| w.name == name) {
+            if let Err(e) = worker.stop() {
+                warn!(worker = worker.name, error = %e, "Worker stopped with an error");
+            }
+
+            let result = (worker.start)();
+            worker.started = Instant::now();
+            worker.started_at = SystemTime::now();
+            worker.failures = 0;
+            match result {
+                Ok(handle) => worker.handle = Some(handle),
+                Err(e) => {
+                    error!(worker = worker.name, error = %e, "Unable to start worker");
+                    worker.last_error = Some(e.to_string());
+                    worker.schedule_restart(Instant::now());
+                }
+            }
+        }
+
+        self.update_status()
+    }
+
+    /// Stop every Worker, in the order they were started.
+    /// Returns the first error, after trying to stop the rest.
+    pub fn stop_all(&mut self) -> WorkerResult {
+        let mut result = Ok(());
+        for worker in self.workers.iter_mut() {
+            if let Err(e) = worker.stop() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        self.update_status()?;
+
+        result
+    }
+
+    fn update_status(&self) -> Result<(), Error> {
+        let workers = self
+            .workers
+            .iter()
+            .map(|w| WorkerStatus {
+                name: w.name,
+                policy: w.policy,
+                state: w.state(),
+                started: w.started_at,
+                restarts: w.restarts,
+                last_error: w.last_error.clone(),
+            })
+            .collect();
+
+        *self
+            .status
+            .0
+            .lock()
+            .or(Err(WorkerError::new_resource_poisoned(
+                "Supervisor.status".to_string(),
+            )))? = SupervisorStatus { workers };
+
+        Ok(())
+    }
+}
+
+impl Supervised {
+    fn state(&self) -> WorkerState {
+        match (&self.handle, self.restart_at) {
+            (Some(_), _) => WorkerState::Running,
+            (None, Some(_)) => WorkerState::Restarting,
+            (None, None) if self.last_error.is_some() => WorkerState::Failed,
+            (None, None) => WorkerState::Stopped,
+        }
+    }
+
+    fn schedule_restart(&mut self, now: Instant) {
+        let delay = match self.policy {
+            RestartPolicy::Never => {
+                warn!(worker = self.name, "Worker won't be restarted");
+                return;
+            }
+            RestartPolicy::Always => Duration::from_millis(0),
+            RestartPolicy::Backoff { initial, max } => {
+                if now.duration_since(self.started) >= max {
+                    self.failures = 0;
+                }
+                let delay = initial
+                    .checked_mul(2u32.saturating_pow(self.failures))
+                    .map_or(max, |d| d.min(max));
+                self.failures += 1;
+                delay
+            }
+        };
+
+        info!(worker = self.name, delay = ?delay, "Scheduled worker restart");
+        self.restart_at = Some(now + delay);
+    }
+
+    fn stop(&mut self) -> WorkerResult {
+        self.restart_at = None;
+        match self.handle.take() {
+            Some(handle) => handle.stop(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SupervisorStatus {
+    pub workers: Vec<WorkerStatus>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerStatus {
+    pub name: &'static str,
+    pub policy: RestartPolicy,
+    pub state: WorkerState,
+    /// When the current, or last, instance of the Worker was started
+    pub started: SystemTime,
+    pub restarts: usize,
+    pub last_error: Option<String>,
+}
+
+impl WorkerStatus {
+    /// Seconds since the Worker started, while it's running.
+    pub fn uptime(&self) -> Option<u64> {
+        match self.state {
+            WorkerState::Running => self.started.elapsed().ok().map(|d| d.as_secs()),
+            _ => None,
+        }
+    }
+}
+
+impl From<WorkerStatus> for protocol::WorkerStatus {
+    fn from(worker: WorkerStatus) -> protocol::WorkerStatus {
+        protocol::WorkerStatus {
+            name: worker.name.to_string(),
+            policy: worker.policy.to_string(),
+            state: worker.state.to_string(),
+            uptime: worker.uptime(),
+            restarts: worker.restarts,
+            last_error: worker.last_error,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WorkerState {
+    Running,
+    /// Failed, and waiting to be restarted
+    Restarting,
+    /// Failed, and won't be restarted, or stopped before it was
+    Failed,
+    /// Finished, or stopped for shutdown
+    Stopped,
+}
+
+impl fmt::Display for WorkerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WorkerState::Running => write!(f, "running"),
+            WorkerState::Restarting => write!(f, "restarting"),
+            WorkerState::Failed => write!(f, "failed"),
+            WorkerState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SupervisorHandle(Arc<Mutex<SupervisorStatus>>);
+
+impl SupervisorHandle {
+    pub fn status(&self) -> Result<SupervisorStatus, Error> {
+        Ok(self
+            .0
+            .lock()
+            .or(Err(WorkerError::new_resource_poisoned(
+                "Supervisor.status".to_string(),
+            )))?
+            .clone())
+    }
+}
+
+/// The sending end of a channel whose receiving end belongs to a supervised Worker.
+///
+/// When that Worker is restarted, it takes a new receiver, and every clone of this sender
+/// starts sending to it. Anything sent while the Worker is down is dropped.
+#[derive(Debug)]
+pub struct SupervisedSender<T>(Arc<Mutex<SenderState<T>>>);
+
+#[derive(Debug)]
+struct SenderState<T> {
+    tx: Sender<T>,
+    /// The receiver of the current channel, until a Worker takes it
+    rx: Option<Receiver<T>>,
+}
+
+impl<T> SupervisedSender<T> {
+    pub fn new() -> SupervisedSender<T> {
+        let (tx, rx) = channel();
+        SupervisedSender(Arc::new(Mutex::new(SenderState { tx, rx: Some(rx) })))
+    }
+
+    pub fn send(&self, t: T) -> Result<(), Error> {
+        let state = self.lock()?;
+        // The receiver hung up, and will be replaced when its Worker restarts
+        let _ = state.tx.send(t);
+
+        Ok(())
+    }
+
+    /// Take the receiving end, for a newly built Worker.
+    /// If a previous Worker already took it, the channel is replaced.
+    pub fn take_receiver(&self) -> Result<Receiver<T>, Error> {
+        let mut state = self.lock()?;
+        if let Some(rx) = state.rx.take() {
+            return Ok(rx);
+        }
+
+        let (tx, rx) = channel();
+        state.tx = tx;
+
+        Ok(rx)
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, SenderState<T>>, Error> {
+        Ok(self.0.lock().or(Err(WorkerError::new_resource_poisoned(
+            "SupervisedSender".to_string(),
+        )))?)
+    }
+}
+
+impl<T> Clone for SupervisedSender<T> {
+    fn clone(&self) -> SupervisedSender<T> {
+        SupervisedSender(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::Shutdown;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    /// Fails straight away, counting how many times it was started.
+    struct Failing(Arc<AtomicUsize>);
+
+    impl Worker for Failing {
+        type W = Failing;
+        const NAME: &'static str = "Failing";
+
+        fn work(self, _shutdown: Shutdown) -> WorkerResult {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(failure::format_err!("Broken"))
+        }
+    }
+
+    fn supervise(policy: RestartPolicy) -> (Supervisor, Arc<AtomicUsize>) {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let mut supervisor = Supervisor::default();
+        let worker_starts = starts.clone();
+        supervisor
+            .supervise(policy, move || Ok(Failing(worker_starts.clone())))
+            .unwrap();
+
+        (supervisor, starts)
+    }
+
+    /// Check on the Workers until the first one's status is `ready`.
+    fn check_until(supervisor: &mut Supervisor, ready: impl Fn(&WorkerStatus) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            supervisor.check().unwrap();
+            if ready(&supervisor.get_handle().status().unwrap().workers[0]) {
+                return;
+            }
+            assert!(Instant::now() < deadline, "Worker status never changed");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn never_leaves_a_failed_worker_stopped() {
+        let (mut supervisor, starts) = supervise(RestartPolicy::Never);
+
+        check_until(&mut supervisor, |worker| {
+            worker.state == WorkerState::Failed
+        });
+        thread::sleep(Duration::from_millis(50));
+        supervisor.check().unwrap();
+
+        let status = supervisor.get_handle().status().unwrap();
+        let worker = &status.workers[0];
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+        assert_eq!(worker.state, WorkerState::Failed);
+        assert_eq!(worker.policy.to_string(), "never");
+        assert_eq!(worker.restarts, 0);
+        assert_eq!(worker.last_error.as_deref(), Some("Broken"));
+        assert_eq!(worker.uptime(), None);
+    }
+
+    #[test]
+    fn always_restarts_a_failed_worker() {
+        let (mut supervisor, starts) = supervise(RestartPolicy::Always);
+
+        check_until(&mut supervisor, |worker| worker.restarts >= 2);
+
+        assert!(starts.load(Ordering::SeqCst) >= 2);
+    }
+}
@@ -1,63 +1,106 @@
+pub mod api;
+pub mod assets;
+pub mod dispatcher;
+pub mod websocket;
 pub mod websockets_server;
 
-use self::websockets_server::WebsocketsServer;
-use crate::counter::CounterHandle;
-use crate::worker::{Worker, WorkerResult};
-use crate::CountUpdateEvent;
+use self::api::{AlbumPath, ApiHandler, ApiHandles, GroupPath, ListQuery};
+use self::assets::EmbeddedAssets;
+use self::websocket::WebsocketHandler;
+use crate::worker::{Shutdown, Worker, WorkerResult};
 use derive_new::new;
+use failure::{format_err, ResultExt};
+use futures::Future;
 use gotham::handler::assets::FileOptions;
 use gotham::router::builder::{build_simple_router, DefineSingleRoute, DrawRoutes};
-use std::env;
-use std::error::Error;
+use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::mpsc::Receiver;
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
 
 #[derive(new)]
 pub struct UIServer {
     web_port: u16,
-    ws_port: u16,
-    cue_rx: Receiver<CountUpdateEvent>,
-    counter_handle: CounterHandle,
+    /// Serve the web UI from here, instead of the one embedded at build time
+    static_dir: Option<PathBuf>,
+    api_handles: ApiHandles,
 }
 
 impl Worker for UIServer {
     type W = UIServer;
     const NAME: &'static str = "UI Server";
 
-    fn work(self) -> WorkerResult {
-        // Start Websockets server
-        let wss = WebsocketsServer::new(self.ws_port, self.cue_rx, self.counter_handle).start()?;
+    fn work(self, shutdown: Shutdown) -> WorkerResult {
+        let router = build_simple_router(|route| {
+            match &self.static_dir {
+                Some(static_dir) => {
+                    route.get("/").to_file(static_dir.join("index.html"));
+                    route.get("assets/*").to_dir(
+                        FileOptions::new(static_dir.join("assets/"))
+                            .with_gzip(true)
+                            .build(),
+                    );
+                }
+                None => {
+                    route.get("/").to_new_handler(EmbeddedAssets);
+                    route.get("assets/*").to_new_handler(EmbeddedAssets);
+                }
+            }
 
-        let static_path = exe_dir()
-            .expect("Unable to find static files directory")
-            .join("static");
+            route
+                .get("/api/status")
+                .to_new_handler(ApiHandler::new(self.api_handles.clone(), api::status));
 
-        let router = build_simple_router(|route| {
-            route.get("/").to_file(static_path.join("index.html"));
+            route
+                .get("/api/groups")
+                .with_query_string_extractor::<ListQuery>()
+                .to_new_handler(ApiHandler::new(self.api_handles.clone(), api::groups));
+
+            route
+                .get("/api/groups/:group/albums")
+                .with_path_extractor::<GroupPath>()
+                .with_query_string_extractor::<ListQuery>()
+                .to_new_handler(ApiHandler::new(self.api_handles.clone(), api::group_albums));
+
+            route
+                .get("/api/groups/:group/albums/:album")
+                .with_path_extractor::<AlbumPath>()
+                .to_new_handler(ApiHandler::new(self.api_handles.clone(), api::album));
+
+            route
+                .get("/api/groups/:group/albums/:album/files")
+                .with_path_extractor::<AlbumPath>()
+                .to_new_handler(ApiHandler::new(self.api_handles.clone(), api::album_files));
 
-            route.get("assets/*").to_dir(
-                FileOptions::new(static_path.join("assets/"))
-                    .with_gzip(true)
-                    .build(),
-            );
+            route
+                .get("/metrics")
+                .to_new_handler(ApiHandler::new(self.api_handles.clone(), api::metrics));
+
+            route
+                .get("/ws")
+                .to_new_handler(WebsocketHandler::new(self.api_handles.clone()));
         });
 
-        gotham::start(format!("127.0.0.1:{}", self.web_port), router);
-        wss.join()?;
+        let web_addr = SocketAddr::from(([127, 0, 0, 1], self.web_port));
+        let listener = TcpListener::bind(&web_addr).context("Unable to bind to web port")?;
 
-        Ok(())
-    }
-}
+        // Serve until shutdown
+        let mut runtime = Runtime::new()?;
+        let server_f = gotham::bind_server(listener, router)
+            .map_err(|_| format_err!("HTTP server stopped unexpectedly"))
+            .select(
+                shutdown
+                    .future()
+                    .map_err(|_| format_err!("Shutdown signal dropped")),
+            )
+            .map(|_| ())
+            .map_err(|(e, _)| e);
+        let result = runtime.block_on(server_f);
+        runtime
+            .shutdown_now()
+            .wait()
+            .map_err(|_| format_err!("Unable to shut down HTTP server"))?;
 
-fn exe_dir() -> Result<PathBuf, Box<Error>> {
-    let exe_path = match env::current_exe() {
-        Ok(path) => path,
-        Err(e) => return Err(format!("Unable to find executable path: {}", e).into()),
-    };
-    let static_path = match exe_path.parent() {
-        Some(path) => path,
-        None => return Err("Unable to find executable directory path".into()),
-    };
-
-    Ok(static_path.into())
+        result
+    }
 }
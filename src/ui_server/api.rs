@@ -0,0 +1,373 @@
+use super::dispatcher::DispatcherHandle;
+use crate::counter::{AlbumCount, CounterHandle, GroupCount};
+use crate::crawler::CrawlerHandle;
+use crate::listener::ListenerHandle;
+use crate::metrics::MetricsWriter;
+use crate::supervisor::SupervisorHandle;
+use derive_new::new;
+use edit_count::protocol;
+use failure::{Error, Fail};
+use gotham::handler::{Handler, HandlerFuture, IntoHandlerFuture, NewHandler};
+use gotham::helpers::http::response::create_response;
+use gotham::state::{FromState, State};
+use gotham_derive::{StateData, StaticResponseExtender};
+use hyper::StatusCode;
+use mime::Mime;
+use serde::Serialize;
+use serde_derive::Deserialize;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// Handles for the state reported by the HTTP API.
+#[derive(Clone, new)]
+pub struct ApiHandles {
+    pub supervisor: SupervisorHandle,
+    pub listener: ListenerHandle,
+    pub crawler: CrawlerHandle,
+    pub counter: CounterHandle,
+    pub dispatcher: DispatcherHandle,
+}
+
+/// Builds the body of an API response, from the request's extracted path and query string.
+type Endpoint = fn(&State, &ApiHandles) -> Result<(Mime, String), Error>;
+
+#[derive(Debug, Fail)]
+pub enum ApiError {
+    #[fail(display = "No group called {:?}", group)]
+    GroupNotFound { group: String },
+
+    #[fail(display = "No album called {:?} in group {:?}", album, group)]
+    AlbumNotFound { group: String, album: String },
+}
+
+/// Serves an `Endpoint`, responding with a JSON error if it fails.
+#[derive(Clone, new)]
+pub struct ApiHandler {
+    handles: ApiHandles,
+    endpoint: Endpoint,
+}
+
+impl NewHandler for ApiHandler {
+    type Instance = ApiHandler;
+
+    fn new_handler(&self) -> gotham::error::Result<ApiHandler> {
+        Ok(self.clone())
+    }
+}
+
+impl Handler for ApiHandler {
+    fn handle(self, state: State) -> Box<HandlerFuture> {
+        let response = match (self.endpoint)(&state, &self.handles) {
+            Ok((mime, body)) => create_response(&state, StatusCode::OK, mime, body),
+            Err(e) => {
+                let status = match e.downcast_ref::<ApiError>() {
+                    Some(_) => StatusCode::NOT_FOUND,
+                    None => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                let body = protocol::ApiError {
+                    error: e.to_string(),
+                };
+                create_response(
+                    &state,
+                    status,
+                    mime::APPLICATION_JSON,
+                    serde_json::to_string(&body).unwrap_or_default(),
+                )
+            }
+        };
+
+        (state, response).into_handler_future()
+    }
+}
+
+/// `GET /api/status`
+/// The state of every worker, the watched roots, the last crawl, and the connected clients.
+pub fn status(_state: &State, handles: &ApiHandles) -> Result<(Mime, String), Error> {
+    let status = protocol::Status {
+        workers: handles
+            .supervisor
+            .status()?
+            .workers
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        listener: handles.listener.status()?.into(),
+        crawler: handles.crawler.status()?.into(),
+        websockets: protocol::WebsocketsStatus {
+            subscribers: handles.dispatcher.subscriber_count()?,
+        },
+    };
+
+    json(&status)
+}
+
+/// A JSON response body.
+fn json<T: Serialize>(body: &T) -> Result<(Mime, String), Error> {
+    Ok((mime::APPLICATION_JSON, serde_json::to_string(body)?))
+}
+
+/// The `group` in `/api/groups/:group/...`
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct GroupPath {
+    group: String,
+}
+
+/// The `group` and `album` in `/api/groups/:group/albums/:album`
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct AlbumPath {
+    group: String,
+    album: String,
+}
+
+/// How to sort and filter a list of groups or albums.
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct ListQuery {
+    #[serde(default)]
+    sort: Sort,
+    /// Only list those that aren't complete
+    #[serde(default)]
+    incomplete: bool,
+}
+
+#[derive(Debug, Default, Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Sort {
+    /// Alphabetical
+    #[default]
+    Name,
+    /// Least complete first
+    Completion,
+    /// Most recently changed first
+    LastActivity,
+}
+
+/// What a list of groups or albums can be sorted and filtered by.
+trait Listed {
+    fn name(&self) -> &str;
+    fn completion(&self) -> f64;
+    fn is_complete(&self) -> bool;
+    fn last_activity(&self) -> Option<SystemTime>;
+}
+
+impl Listed for GroupCount {
+    fn name(&self) -> &str {
+        &self.group_name
+    }
+
+    fn completion(&self) -> f64 {
+        self.completion()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.is_complete()
+    }
+
+    fn last_activity(&self) -> Option<SystemTime> {
+        self.last_activity
+    }
+}
+
+impl Listed for AlbumCount {
+    fn name(&self) -> &str {
+        &self.album_name
+    }
+
+    fn completion(&self) -> f64 {
+        self.completion()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.is_complete()
+    }
+
+    fn last_activity(&self) -> Option<SystemTime> {
+        self.last_activity
+    }
+}
+
+/// Sort and filter `items` as asked for by `query`, as the protocol type `S`.
+fn list<T, S>(mut items: Vec<T>, query: &ListQuery) -> Vec<S>
+where
+    T: Listed + Into<S>,
+{
+    if query.incomplete {
+        items.retain(|i| !i.is_complete());
+    }
+
+    items.sort_by(|a, b| {
+        let order = match query.sort {
+            Sort::Name => Ordering::Equal,
+            Sort::Completion => a
+                .completion()
+                .partial_cmp(&b.completion())
+                .unwrap_or(Ordering::Equal),
+            // Reversed, and None sorts before any time, so no activity is last
+            Sort::LastActivity => b.last_activity().cmp(&a.last_activity()),
+        };
+        order.then_with(|| a.name().cmp(b.name()))
+    });
+
+    items.into_iter().map(Into::into).collect()
+}
+
+/// `GET /api/groups`
+/// The counts for each group, totalled over its albums.
+pub fn groups(state: &State, handles: &ApiHandles) -> Result<(Mime, String), Error> {
+    let groups = handles.counter.group_counts()?;
+
+    let groups: Vec<protocol::GroupSummary> = list(groups, ListQuery::borrow_from(state));
+
+    json(&groups)
+}
+
+/// `GET /api/groups/:group/albums`
+/// The counts for each album in a group.
+pub fn group_albums(state: &State, handles: &ApiHandles) -> Result<(Mime, String), Error> {
+    let GroupPath { group } = GroupPath::borrow_from(state);
+    let albums = handles
+        .counter
+        .group_albums(group)?
+        .ok_or_else(|| ApiError::GroupNotFound {
+            group: group.clone(),
+        })?;
+
+    let albums: Vec<protocol::AlbumSummary> = list(albums, ListQuery::borrow_from(state));
+
+    json(&albums)
+}
+
+/// `GET /api/groups/:group/albums/:album`
+/// The counts for one album.
+pub fn album(state: &State, handles: &ApiHandles) -> Result<(Mime, String), Error> {
+    let AlbumPath { group, album } = AlbumPath::borrow_from(state);
+    let count =
+        handles
+            .counter
+            .album_count(group, album)?
+            .ok_or_else(|| ApiError::AlbumNotFound {
+                group: group.clone(),
+                album: album.clone(),
+            })?;
+
+    json(&protocol::AlbumSummary::from(count))
+}
+
+/// `GET /api/groups/:group/albums/:album/files`
+/// What still needs editing in one album.
+pub fn album_files(state: &State, handles: &ApiHandles) -> Result<(Mime, String), Error> {
+    let AlbumPath { group, album } = AlbumPath::borrow_from(state);
+    let files =
+        handles
+            .counter
+            .album_files(group, album)?
+            .ok_or_else(|| ApiError::AlbumNotFound {
+                group: group.clone(),
+                album: album.clone(),
+            })?;
+
+    json(&protocol::AlbumFiles::from(files))
+}
+
+/// `GET /metrics`
+/// Counts and worker health in the Prometheus text format.
+pub fn metrics(_state: &State, handles: &ApiHandles) -> Result<(Mime, String), Error> {
+    let mut w = MetricsWriter::new();
+
+    let albums = handles.counter.album_counts()?;
+
+    w.family(
+        "edit_count_album_files",
+        "gauge",
+        "Files in an album, by kind. Edited is raw files with a render of the same name.",
+    );
+    for album in albums.iter() {
+        for (kind, value) in album.kinds().iter() {
+            w.sample(
+                "edit_count_album_files",
+                &[
+                    ("group", &album.group_name),
+                    ("album", &album.album_name),
+                    ("kind", kind),
+                ],
+                *value as f64,
+            );
+        }
+    }
+
+    // Sorted, so the output is stable between scrapes
+    let mut groups: BTreeMap<&str, BTreeMap<&str, usize>> = BTreeMap::new();
+    for album in albums.iter() {
+        let group = groups.entry(&album.group_name).or_default();
+        for (kind, value) in album.kinds().iter() {
+            *group.entry(kind).or_default() += value;
+        }
+    }
+    w.family(
+        "edit_count_group_files",
+        "gauge",
+        "Files in all albums of a group, by kind.",
+    );
+    for (group, kinds) in groups {
+        for (kind, value) in kinds {
+            w.sample(
+                "edit_count_group_files",
+                &[("group", group), ("kind", kind)],
+                value as f64,
+            );
+        }
+    }
+
+    let listener = handles.listener.status()?;
+    w.family(
+        "edit_count_filesystem_events_total",
+        "counter",
+        "Filesystem events processed by the Listener.",
+    );
+    w.sample(
+        "edit_count_filesystem_events_total",
+        &[],
+        listener.events_processed as f64,
+    );
+
+    let crawler = handles.crawler.status()?;
+    w.family(
+        "edit_count_crawl_duration_seconds",
+        "histogram",
+        "Time taken to crawl a layout root.",
+    );
+    w.histogram(
+        "edit_count_crawl_duration_seconds",
+        &crawler.crawl_durations,
+    );
+
+    w.family(
+        "edit_count_websocket_clients",
+        "gauge",
+        "Connected web UI clients.",
+    );
+    w.sample(
+        "edit_count_websocket_clients",
+        &[],
+        handles.dispatcher.subscriber_count()? as f64,
+    );
+
+    w.family(
+        "edit_count_worker_restarts_total",
+        "counter",
+        "Times a worker has been restarted after failing.",
+    );
+    for worker in handles.supervisor.status()?.workers {
+        w.sample(
+            "edit_count_worker_restarts_total",
+            &[("worker", worker.name)],
+            worker.restarts as f64,
+        );
+    }
+
+    let mime = "text/plain; version=0.0.4"
+        .parse()
+        .expect("Prometheus content type is valid");
+
+    Ok((mime, w.finish()))
+}
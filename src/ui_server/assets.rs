@@ -0,0 +1,108 @@
+use gotham::handler::{Handler, HandlerFuture, IntoHandlerFuture, NewHandler};
+use gotham::helpers::http::response::{create_empty_response, create_response};
+use gotham::state::{FromState, State};
+use hyper::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, VARY};
+use hyper::{StatusCode, Uri};
+use mime_guess::guess_mime_type;
+
+/// The web UI, embedded from `static/` by build.rs.
+/// Each file's path relative to `static/`, and its contents.
+static ASSETS: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+fn find(name: &str) -> Option<&'static [u8]> {
+    ASSETS
+        .iter()
+        .find(|(path, _)| *path == name)
+        .map(|(_, contents)| *contents)
+}
+
+/// Serves the embedded web UI, with `/` serving `index.html`.
+/// Like gotham's `FileOptions::with_gzip`, a file's `.gz` version is sent instead
+/// if there is one, and the client accepts gzip.
+#[derive(Clone, Copy)]
+pub struct EmbeddedAssets;
+
+impl NewHandler for EmbeddedAssets {
+    type Instance = EmbeddedAssets;
+
+    fn new_handler(&self) -> gotham::error::Result<EmbeddedAssets> {
+        Ok(*self)
+    }
+}
+
+impl Handler for EmbeddedAssets {
+    fn handle(self, state: State) -> Box<HandlerFuture> {
+        let name = match Uri::borrow_from(&state).path().trim_start_matches('/') {
+            "" => "index.html",
+            path => path,
+        };
+
+        let gzipped = if accepts_gzip(HeaderMap::borrow_from(&state)) {
+            find(&format!("{}.gz", name))
+        } else {
+            None
+        };
+
+        let response = match (gzipped, find(name)) {
+            (Some(contents), _) => {
+                let mut response =
+                    create_response(&state, StatusCode::OK, guess_mime_type(name), contents);
+                let headers = response.headers_mut();
+                headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+                response
+            }
+            (None, Some(contents)) => {
+                create_response(&state, StatusCode::OK, guess_mime_type(name), contents)
+            }
+            (None, None) => create_empty_response(&state, StatusCode::NOT_FOUND),
+        };
+
+        (state, response).into_handler_future()
+    }
+}
+
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            // `gzip;q=0` means gzip isn't accepted
+            params.next() == Some("gzip")
+                && params.all(|p| match p.starts_with("q=") {
+                    true => p[2..].parse::<f32>().map_or(true, |q| q > 0.0),
+                    false => true,
+                })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(values: &[&'static str]) -> bool {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        }
+        accepts_gzip(&headers)
+    }
+
+    #[test]
+    fn gzip_is_accepted_when_listed() {
+        assert!(accepts(&["gzip"]));
+        assert!(accepts(&["deflate, gzip;q=0.5, br"]));
+        assert!(accepts(&["br", "gzip"]));
+    }
+
+    #[test]
+    fn gzip_is_refused_when_missing_or_weighted_zero() {
+        assert!(!accepts(&[]));
+        assert!(!accepts(&["deflate, br"]));
+        assert!(!accepts(&["gzip;q=0"]));
+        assert!(!accepts(&["x-gzip"]));
+    }
+}
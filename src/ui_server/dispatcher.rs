@@ -0,0 +1,359 @@
+use crate::worker::{Shutdown, Worker, WorkerError, WorkerResult, SHUTDOWN_CHECK_INTERVAL};
+use crate::{CountUpdateEvent, WarningEvent};
+use derive_new::new;
+use edit_count::protocol::{AlbumCount, Message, RemovedAlbum, Response, Subscription};
+use failure::Error;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use websocket::OwnedMessage;
+
+/// Sends count updates to the websocket clients that subscribed to them,
+/// and warnings to every client, whichever port they connected on.
+#[derive(new)]
+pub struct Dispatcher {
+    cue_rx: Receiver<CountUpdateEvent>,
+    warning_rx: Receiver<WarningEvent>,
+    /// Shared with the websocket servers, which add a subscriber for each client
+    subscribers: DispatcherHandle,
+}
+
+impl Worker for Dispatcher {
+    type W = Dispatcher;
+    const NAME: &'static str = "Dispatcher";
+
+    fn work(self, shutdown: Shutdown) -> WorkerResult {
+        loop {
+            match self.cue_rx.recv_timeout(SHUTDOWN_CHECK_INTERVAL) {
+                Ok(cue) if cue.removed => self.subscribers.send_removal(cue.into())?,
+                Ok(cue) => self.subscribers.send_update(cue.into())?,
+                Err(RecvTimeoutError::Timeout) if shutdown.is_triggered() => break,
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            // Warnings are rare, so they are sent between count updates
+            for warning in self.warning_rx.try_iter() {
+                self.subscribers.broadcast(warning.into())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The connected websocket clients.
+#[derive(Clone, Default)]
+pub struct DispatcherHandle(Arc<Mutex<Subscribers>>);
+
+#[derive(Default)]
+struct Subscribers {
+    clients: HashMap<usize, Subscriber>,
+    next_id: usize,
+}
+
+/// A connected client, and the count updates it wants.
+struct Subscriber {
+    tx: UnboundedSender<OwnedMessage>,
+    /// Set once the client has sent `hello`. Nothing is sent to it before then.
+    ready: bool,
+    /// By subscription id. Without any, every update is sent.
+    filters: HashMap<u64, Filter>,
+    next_filter: u64,
+    /// Albums last sent as incomplete, so the update completing them is sent too
+    incomplete: HashSet<(String, String)>,
+}
+
+impl Subscriber {
+    fn wants(&self, update: &AlbumCount) -> bool {
+        if !self.ready {
+            return false;
+        }
+
+        let key = (update.group_name.clone(), update.album_name.clone());
+        let was_incomplete = self.incomplete.contains(&key);
+
+        self.filters.is_empty()
+            || self
+                .filters
+                .values()
+                .any(|f| f.matches(update, was_incomplete))
+    }
+}
+
+/// The count updates wanted by one subscription.
+pub struct Filter {
+    groups: Option<HashSet<String>>,
+    album: Option<Regex>,
+    incomplete: bool,
+}
+
+impl Filter {
+    pub fn new(subscription: Subscription) -> Filter {
+        Filter {
+            groups: subscription.groups.map(|g| g.into_iter().collect()),
+            album: subscription.album.map(|glob| glob_regex(&glob)),
+            incomplete: subscription.incomplete,
+        }
+    }
+
+    /// Whether `update` should be sent, given whether the album was last sent as incomplete.
+    fn matches(&self, update: &AlbumCount, was_incomplete: bool) -> bool {
+        let incomplete = !self.incomplete || !update.is_complete() || was_incomplete;
+
+        self.matches_album(&update.group_name, &update.album_name) && incomplete
+    }
+
+    /// Whether the album is in the subscription's groups, and matches its glob.
+    fn matches_album(&self, group_name: &str, album_name: &str) -> bool {
+        let group = self.groups.as_ref().is_none_or(|g| g.contains(group_name));
+        let album = self.album.as_ref().is_none_or(|a| a.is_match(album_name));
+
+        group && album
+    }
+}
+
+/// Match a whole name against a glob, where `*` matches anything and `?` one character.
+fn glob_regex(glob: &str) -> Regex {
+    let pattern = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("^{}$", pattern)).expect("Escaped globs are valid patterns")
+}
+
+impl DispatcherHandle {
+    /// Add a client, returning its id, and the stream of messages to send it.
+    /// Nothing is sent to it until it is marked ready.
+    pub fn subscribe(&self) -> Result<(usize, UnboundedReceiver<OwnedMessage>), Error> {
+        let (tx, rx) = unbounded();
+        let mut subscribers = self.lock()?;
+        let id = subscribers.next_id;
+        subscribers.next_id += 1;
+        subscribers.clients.insert(
+            id,
+            Subscriber {
+                tx,
+                ready: false,
+                filters: HashMap::new(),
+                next_filter: 1,
+                incomplete: HashSet::new(),
+            },
+        );
+
+        Ok((id, rx))
+    }
+
+    /// Start sending a client updates and warnings, once it has completed the handshake.
+    pub fn ready(&self, client: usize) -> Result<(), Error> {
+        if let Some(subscriber) = self.lock()?.clients.get_mut(&client) {
+            subscriber.ready = true;
+        }
+
+        Ok(())
+    }
+
+    /// Only send a client the count updates matching `filter`, or its other filters.
+    /// Returns the subscription id, or None if the client has hung up.
+    pub fn add_filter(&self, client: usize, filter: Filter) -> Result<Option<u64>, Error> {
+        let mut subscribers = self.lock()?;
+        let subscriber = match subscribers.clients.get_mut(&client) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+
+        let id = subscriber.next_filter;
+        subscriber.next_filter += 1;
+        subscriber.filters.insert(id, filter);
+
+        Ok(Some(id))
+    }
+
+    /// Remove one of a client's filters, or all of them, returning the ids removed.
+    pub fn remove_filters(&self, client: usize, filter: Option<u64>) -> Result<Vec<u64>, Error> {
+        let mut subscribers = self.lock()?;
+        let filters = match subscribers.clients.get_mut(&client) {
+            Some(s) => &mut s.filters,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut removed: Vec<u64> = match filter {
+            Some(id) => filters.remove(&id).map(|_| id).into_iter().collect(),
+            None => filters.drain().map(|(id, _)| id).collect(),
+        };
+        removed.sort();
+
+        Ok(removed)
+    }
+
+    /// The number of connected clients.
+    pub fn subscriber_count(&self) -> Result<usize, Error> {
+        Ok(self
+            .lock()?
+            .clients
+            .values()
+            // Clients that hung up are only removed on the next broadcast
+            .filter(|s| !s.tx.is_closed())
+            .count())
+    }
+
+    /// Send `update` to every client that wants it, dropping any that have hung up.
+    fn send_update(&self, update: AlbumCount) -> WorkerResult {
+        let key = (update.group_name.clone(), update.album_name.clone());
+        let complete = update.is_complete();
+        let message = to_message(Message::CountUpdate(update.clone()))?;
+        self.lock()?.clients.retain(|_, sub| {
+            if !sub.wants(&update) {
+                return !sub.tx.is_closed();
+            }
+
+            if complete {
+                sub.incomplete.remove(&key);
+            } else {
+                sub.incomplete.insert(key.clone());
+            }
+            sub.tx.unbounded_send(message.clone()).is_ok()
+        });
+
+        Ok(())
+    }
+
+    /// Send `removed` to every client subscribed to the album, dropping any that have hung up.
+    /// Whether the album was complete doesn't matter, as it may have been sent either way.
+    fn send_removal(&self, removed: RemovedAlbum) -> WorkerResult {
+        let key = (removed.group_name.clone(), removed.album_name.clone());
+        let message = to_message(Message::AlbumRemoved(removed))?;
+        self.lock()?.clients.retain(|_, sub| {
+            sub.incomplete.remove(&key);
+            let wants = sub.ready
+                && (sub.filters.is_empty()
+                    || sub
+                        .filters
+                        .values()
+                        .any(|f| f.matches_album(&key.0, &key.1)));
+            if !wants {
+                return !sub.tx.is_closed();
+            }
+
+            sub.tx.unbounded_send(message.clone()).is_ok()
+        });
+
+        Ok(())
+    }
+
+    /// Send `message` to every ready client, dropping any that have hung up.
+    fn broadcast(&self, message: Message) -> WorkerResult {
+        let message = to_message(message)?;
+        self.lock()?.clients.retain(|_, sub| {
+            if !sub.ready {
+                return !sub.tx.is_closed();
+            }
+            sub.tx.unbounded_send(message.clone()).is_ok()
+        });
+
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, Subscribers>, Error> {
+        Ok(self.0.lock().or(Err(WorkerError::new_resource_poisoned(
+            "Dispatcher.subscribers".to_string(),
+        )))?)
+    }
+}
+
+fn to_message(message: Message) -> Result<OwnedMessage, Error> {
+    let response = Response { id: None, message };
+    Ok(OwnedMessage::Text(serde_json::to_string(&response)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    fn update(album_name: &str, raw: usize, edited: usize) -> AlbumCount {
+        AlbumCount {
+            group_name: "2024".to_string(),
+            album_name: album_name.to_string(),
+            total: raw,
+            raw,
+            render: edited,
+            edited,
+            offline: false,
+            offline_since: None,
+            events: 1,
+            sequence: 1,
+        }
+    }
+
+    /// The albums of the count updates sent to a client, once the dispatcher is dropped.
+    fn received(rx: UnboundedReceiver<OwnedMessage>) -> Vec<String> {
+        rx.collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|message| match message {
+                OwnedMessage::Text(text) => text,
+                message => panic!("Unexpected message {:?}", message),
+            })
+            .map(|text| serde_json::from_str::<serde_json::Value>(&text).unwrap())
+            .map(|response| response["album_name"].as_str().unwrap_or("").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn globs_match_whole_names() {
+        let glob = glob_regex("Wedding*");
+        assert!(glob.is_match("Wedding"));
+        assert!(glob.is_match("Wedding Reception"));
+        assert!(!glob.is_match("The Wedding"));
+
+        let glob = glob_regex("IMG_????");
+        assert!(glob.is_match("IMG_0001"));
+        assert!(!glob.is_match("IMG_00001"));
+
+        // Everything else is literal
+        let glob = glob_regex("Party (2).[a]+");
+        assert!(glob.is_match("Party (2).[a]+"));
+        assert!(!glob.is_match("Party 2 a"));
+    }
+
+    #[test]
+    fn nothing_is_sent_before_hello() {
+        let dispatcher = DispatcherHandle::default();
+        let (client, rx) = dispatcher.subscribe().unwrap();
+
+        dispatcher.send_update(update("Wedding", 2, 0)).unwrap();
+        dispatcher
+            .broadcast(Message::Warning {
+                source: "Listener".to_string(),
+                message: "Ran out of watches".to_string(),
+            })
+            .unwrap();
+        dispatcher.ready(client).unwrap();
+        dispatcher.send_update(update("Party", 2, 0)).unwrap();
+        drop(dispatcher);
+
+        assert_eq!(received(rx), vec!["Party"]);
+    }
+
+    #[test]
+    fn incomplete_subscriptions_get_the_update_completing_an_album() {
+        let dispatcher = DispatcherHandle::default();
+        let (client, rx) = dispatcher.subscribe().unwrap();
+        dispatcher.ready(client).unwrap();
+        let filter = Filter::new(Subscription {
+            groups: Some(vec!["2024".to_string()]),
+            album: None,
+            incomplete: true,
+        });
+        dispatcher.add_filter(client, filter).unwrap();
+
+        dispatcher.send_update(update("Wedding", 2, 1)).unwrap();
+        dispatcher.send_update(update("Wedding", 2, 2)).unwrap();
+        dispatcher.send_update(update("Wedding", 2, 2)).unwrap();
+        dispatcher.send_update(update("Party", 2, 2)).unwrap();
+        drop(dispatcher);
+
+        assert_eq!(received(rx), vec!["Wedding", "Wedding"]);
+    }
+}
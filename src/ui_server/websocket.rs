@@ -0,0 +1,320 @@
+use super::api::ApiHandles;
+use super::dispatcher::Filter;
+use crate::counter::Catchup;
+use derive_new::new;
+use edit_count::protocol::{Command, ErrorCode, Message, Request, Response, PROTOCOL_VERSION};
+use failure::{format_err, Error};
+use futures::{future, Future, Sink, Stream};
+use gotham::handler::{Handler, HandlerFuture, IntoHandlerFuture, NewHandler};
+use gotham::helpers::http::response::{create_empty_response, create_response};
+use gotham::state::{FromState, State};
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY,
+    SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use hyper::{Body, StatusCode};
+use serde_json::Value;
+use std::str::FromStr;
+use tokio::codec::Framed;
+use tracing::{info, warn};
+use websocket::header::{WebSocketAccept, WebSocketKey};
+use websocket::r#async::server::upgrade::Upgrade;
+use websocket::r#async::{MessageCodec, MsgCodecCtx, Stream as AsyncStream};
+use websocket::{OwnedMessage, WebSocketError};
+
+/// The subprotocol the web UI speaks. Clients that don't ask for it are rejected.
+pub const PROTOCOL: &str = "ec-ws";
+
+/// Serves `GET /ws` on the web UI port.
+/// Answers the handshake with `101 Switching Protocols`, then serves the client
+/// on the upgraded connection until it disconnects.
+#[derive(Clone, new)]
+pub struct WebsocketHandler {
+    handles: ApiHandles,
+}
+
+impl NewHandler for WebsocketHandler {
+    type Instance = WebsocketHandler;
+
+    fn new_handler(&self) -> gotham::error::Result<WebsocketHandler> {
+        Ok(self.clone())
+    }
+}
+
+impl Handler for WebsocketHandler {
+    fn handle(self, mut state: State) -> Box<HandlerFuture> {
+        let accept = match handshake(HeaderMap::borrow_from(&state)) {
+            Ok(accept) => accept,
+            Err(e) => {
+                info!(error = %e, "Rejected websocket");
+                let response = create_response(
+                    &state,
+                    StatusCode::BAD_REQUEST,
+                    mime::TEXT_PLAIN,
+                    e.to_string(),
+                );
+                return (state, response).into_handler_future();
+            }
+        };
+
+        // The connection is handed over once the response has been sent
+        let handles = self.handles;
+        let client_f = Body::take_from(&mut state)
+            .on_upgrade()
+            .map_err(|e| -> Error { e.into() })
+            .and_then(move |upgraded| {
+                info!("Websocket connection");
+                let client = Framed::new(upgraded, MessageCodec::default(MsgCodecCtx::Server));
+                match serve_client(client, handles) {
+                    Ok(connection_f) => future::Either::A(connection_f),
+                    Err(e) => future::Either::B(future::err(e)),
+                }
+            })
+            .map_err(|e| warn!(error = %e, "Websocket connection"));
+        tokio::spawn(client_f);
+
+        let mut response = create_empty_response(&state, StatusCode::SWITCHING_PROTOCOLS);
+        let headers = response.headers_mut();
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(PROTOCOL));
+        headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+
+        (state, response).into_handler_future()
+    }
+}
+
+/// Check that `headers` ask for a websocket speaking `PROTOCOL`,
+/// returning the `Sec-WebSocket-Accept` that answers them.
+fn handshake(headers: &HeaderMap) -> Result<HeaderValue, Error> {
+    let header = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim().to_ascii_lowercase())
+            .collect::<Vec<String>>()
+    };
+
+    if !header(UPGRADE).iter().any(|v| v == "websocket")
+        || !header(CONNECTION).iter().any(|v| v == "upgrade")
+    {
+        return Err(format_err!("Not a websocket upgrade"));
+    }
+    if header(SEC_WEBSOCKET_VERSION) != ["13"] {
+        return Err(format_err!("Unsupported websocket version"));
+    }
+    if !header(SEC_WEBSOCKET_PROTOCOL).iter().any(|v| v == PROTOCOL) {
+        return Err(format_err!("Missing the {} protocol", PROTOCOL));
+    }
+
+    let key = headers
+        .get(SEC_WEBSOCKET_KEY)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| WebSocketKey::from_str(v).ok())
+        .ok_or_else(|| format_err!("Invalid Sec-WebSocket-Key"))?;
+
+    Ok(HeaderValue::from_str(
+        &WebSocketAccept::new(&key).serialize(),
+    )?)
+}
+
+/// Finish the handshake if the client asked for `PROTOCOL`, then serve it until it disconnects.
+pub fn accept<S>(
+    upgrade: Upgrade<S>,
+    handles: ApiHandles,
+) -> Box<dyn Future<Item = (), Error = Error> + Send>
+where
+    S: AsyncStream + Send + 'static,
+{
+    if !upgrade.protocols().iter().any(|s| s == PROTOCOL) {
+        info!("Rejected websocket without the {} protocol", PROTOCOL);
+        return Box::new(upgrade.reject().map(|_| ()).map_err(Error::from));
+    }
+
+    Box::new(
+        upgrade
+            .use_protocol(PROTOCOL)
+            .accept()
+            .map_err(|e| -> Error { e.into() })
+            .and_then(move |(client, _)| match serve_client(client, handles) {
+                Ok(connection_f) => future::Either::A(connection_f),
+                Err(e) => future::Either::B(future::err(e)),
+            }),
+    )
+}
+
+/// Answer a connected client's commands, and send it every broadcast, until it disconnects.
+fn serve_client<C>(
+    client: C,
+    handles: ApiHandles,
+) -> Result<impl Future<Item = (), Error = Error>, Error>
+where
+    C: Stream<Item = OwnedMessage, Error = WebSocketError>
+        + Sink<SinkItem = OwnedMessage, SinkError = WebSocketError>,
+{
+    let (subscriber, response_rx) = handles.dispatcher.subscribe()?;
+    let response_rx = response_rx.map_err(|_| format_err!("Error encountered on response stream"));
+
+    let mut session = Session::new(subscriber);
+    let (sink, stream) = client.split();
+    let connection_f = stream
+        .take_while(|m| Ok(!m.is_close()))
+        .map_err(|e| -> Error { e.into() })
+        .and_then(move |m| match m {
+            OwnedMessage::Ping(p) => Ok(Some(OwnedMessage::Pong(p))),
+            OwnedMessage::Text(payload) => {
+                let response = session.handle(&payload, &handles);
+                Ok(Some(OwnedMessage::Text(serde_json::to_string(&response)?)))
+            }
+            _ => Ok(None),
+        })
+        .filter_map(|m| m)
+        .select(response_rx)
+        .forward(sink.sink_map_err(|e| -> Error { e.into() }))
+        .and_then(|(_, sink)| sink.send(OwnedMessage::Close(None)))
+        .map(|_| ());
+
+    Ok(connection_f)
+}
+
+/// What the server knows about a connected client.
+struct Session {
+    /// The protocol version agreed in `hello`
+    version: Option<u32>,
+    /// The client's id with the Dispatcher
+    subscriber: usize,
+}
+
+impl Session {
+    fn new(subscriber: usize) -> Session {
+        Session {
+            version: None,
+            subscriber,
+        }
+    }
+
+    /// Answer one request, replying with an error if it can't be answered.
+    fn handle(&mut self, payload: &str, handles: &ApiHandles) -> Response {
+        let value: Value = match serde_json::from_str(payload) {
+            Ok(value) => value,
+            Err(e) => return error_response(None, ErrorCode::InvalidRequest, e.to_string()),
+        };
+        // Read on its own, so an invalid request's error can still be matched to it
+        let id = value.get("id").and_then(Value::as_u64);
+
+        let request: Request = match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => return error_response(id, ErrorCode::InvalidRequest, e.to_string()),
+        };
+
+        match self.answer(request.command, handles) {
+            Ok(message) => Response { id, message },
+            Err(e) => error_response(id, ErrorCode::Internal, e.to_string()),
+        }
+    }
+
+    fn answer(&mut self, command: Command, handles: &ApiHandles) -> Result<Message, Error> {
+        let message = match command {
+            Command::Hello { version } if version == PROTOCOL_VERSION => {
+                self.version = Some(version);
+                handles.dispatcher.ready(self.subscriber)?;
+                Message::Hello { version }
+            }
+            Command::Hello { version } => error(
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "Version {} isn't supported, the server speaks version {}",
+                    version, PROTOCOL_VERSION
+                ),
+            ),
+            _ if self.version.is_none() => error(
+                ErrorCode::HandshakeRequired,
+                "Send hello before any other command".to_string(),
+            ),
+
+            Command::FullCount => full_count(handles.counter.snapshot()?),
+            Command::Resume { nonce, since } => {
+                let catchup = handles.counter.changes_since(nonce, since)?;
+                if catchup.partial {
+                    let (removed, albums): (Vec<_>, Vec<_>) =
+                        catchup.albums.into_iter().partition(|a| a.removed);
+                    Message::Changes {
+                        nonce: catchup.nonce,
+                        sequence: catchup.sequence,
+                        albums: albums.into_iter().map(Into::into).collect(),
+                        removed: removed.into_iter().map(Into::into).collect(),
+                    }
+                } else {
+                    full_count(catchup)
+                }
+            }
+            Command::ListenerStatus => {
+                let status = handles.listener.status()?;
+                Message::ListenerStatus {
+                    roots: status.roots.into_iter().map(Into::into).collect(),
+                    events_processed: status.events_processed,
+                }
+            }
+            Command::WorkerStatus => Message::WorkerStatus {
+                workers: handles
+                    .supervisor
+                    .status()?
+                    .workers
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            },
+            Command::AlbumFiles { group, album } => {
+                match handles.counter.album_files(&group, &album)? {
+                    Some(files) => Message::AlbumFiles(files.into()),
+                    None => error(
+                        ErrorCode::NotFound,
+                        format!("No album called {:?} in group {:?}", album, group),
+                    ),
+                }
+            }
+            Command::Subscribe(subscription) => {
+                let filter = Filter::new(subscription);
+                match handles.dispatcher.add_filter(self.subscriber, filter)? {
+                    Some(subscription) => Message::Subscribed { subscription },
+                    None => return Err(format_err!("Client is no longer subscribed")),
+                }
+            }
+            Command::Unsubscribe { subscription } => {
+                let subscriptions = handles
+                    .dispatcher
+                    .remove_filters(self.subscriber, subscription)?;
+                match subscription {
+                    Some(id) if subscriptions.is_empty() => error(
+                        ErrorCode::NotFound,
+                        format!("No subscription with id {}", id),
+                    ),
+                    _ => Message::Unsubscribed { subscriptions },
+                }
+            }
+        };
+
+        Ok(message)
+    }
+}
+
+fn full_count(catchup: Catchup) -> Message {
+    Message::FullCount {
+        nonce: catchup.nonce,
+        sequence: catchup.sequence,
+        albums: catchup.albums.into_iter().map(Into::into).collect(),
+    }
+}
+
+fn error(code: ErrorCode, message: String) -> Message {
+    Message::Error { code, message }
+}
+
+fn error_response(id: Option<u64>, code: ErrorCode, message: String) -> Response {
+    Response {
+        id,
+        message: error(code, message),
+    }
+}
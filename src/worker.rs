@@ -1,6 +1,14 @@
 use derive_new::new;
 use failure::{Error, Fail};
+use futures::sync::oneshot;
+use futures::Future;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
 use std::thread;
+use std::time::Duration;
+use tracing::{info, info_span, warn};
+
+/// How often Workers blocked on a channel check whether they should stop
+pub const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
 
 pub trait Worker
 where
@@ -13,36 +21,102 @@ where
     where
         Self: 'static,
     {
+        let shutdown = Shutdown::default();
+        let worker_shutdown = shutdown.clone();
         let handle = thread::Builder::new()
             .name(Self::NAME.to_string())
-            .spawn(move || self.work())?;
+            .spawn(move || {
+                // Tag everything logged by the Worker with its name
+                let _span = info_span!("worker", worker = Self::NAME).entered();
+                self.work(worker_shutdown)
+            })?;
 
         Ok(WorkerHandle {
             handle,
             name: Self::NAME,
+            shutdown,
         })
     }
 
-    fn work(self) -> WorkerResult
+    /// Do the Worker's job until it is finished, or `shutdown` is triggered.
+    /// Any state should be flushed before returning.
+    fn work(self, shutdown: Shutdown) -> WorkerResult
     where
         Self: Sized;
 }
 
+/// Tells a Worker to stop. Clones share the same signal.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown(Arc<(Mutex<bool>, Condvar)>);
+
+impl Shutdown {
+    pub fn trigger(&self) {
+        let (triggered, condvar) = &*self.0;
+        // A bool can't be left half written, so a poisoned lock is still usable
+        *triggered.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0 .0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sleep for `timeout`, waking early if shutdown is triggered.
+    /// Returns whether shutdown has been triggered.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (triggered, condvar) = &*self.0;
+        let guard = triggered.lock().unwrap_or_else(PoisonError::into_inner);
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |triggered| !*triggered)
+            .unwrap_or_else(PoisonError::into_inner);
+
+        *guard
+    }
+
+    /// A future that resolves once shutdown is triggered, for Workers running an event loop.
+    pub fn future(&self) -> impl Future<Item = (), Error = ()> {
+        let (tx, rx) = oneshot::channel();
+        let shutdown = self.clone();
+        thread::spawn(move || {
+            let (triggered, condvar) = &*shutdown.0;
+            let guard = triggered.lock().unwrap_or_else(PoisonError::into_inner);
+            let _guard = condvar
+                .wait_while(guard, |triggered| !*triggered)
+                .unwrap_or_else(PoisonError::into_inner);
+            let _ = tx.send(());
+        });
+
+        rx.map_err(|_| ())
+    }
+}
+
 #[derive(Debug)]
 pub struct WorkerHandle {
     handle: thread::JoinHandle<WorkerResult>,
     name: &'static str,
+    shutdown: Shutdown,
 }
 
 impl WorkerHandle {
+    /// Whether the Worker has returned, or panicked.
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Tell the Worker to stop, and wait for it to finish.
+    pub fn stop(self) -> WorkerResult {
+        self.shutdown.trigger();
+        self.join()
+    }
+
     pub fn join(self) -> WorkerResult {
         let result: WorkerResult = self.handle.join().or(Err(WorkerError::ThreadPanicked {
             name: self.name.to_string(),
         }))?;
         if result.is_err() {
-            println!("Error in {}, terminating.", self.name);
+            warn!(worker = self.name, "Worker stopped with an error");
         } else {
-            println!("{} shutting down.", self.name);
+            info!(worker = self.name, "Worker shut down");
         }
 
         result